@@ -13,7 +13,7 @@ rustPlatform.buildRustPackage {
   src = lib.cleanSource ./.;
 
   uesFetchCargoVendor = true;
-  cargoHash = "sha256-0rumDPfeZRrHe69TZTBQutJKHDZkfaYXYZSFG7CywfU=";
+  cargoHash = "sha256-DsAutcQrXm192UpgWXJU4vTTUxxHeinV3WeqyaH9788=";
 
   nativeBuildInputs = [
     pkg-config
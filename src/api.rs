@@ -0,0 +1,189 @@
+use crate::history;
+use crate::queue::{Queue, Trigger};
+use crate::registry::Registry;
+use crate::repo::{Repo, RepoState, Schedule};
+use anyhow::{Context, Result};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+#[derive(Debug, Serialize)]
+struct RepoStatus {
+    name: String,
+
+    queued: bool,
+    running: bool,
+
+    #[serde(flatten)]
+    state: RepoState,
+}
+
+impl RepoStatus {
+    fn of(repo: &Repo) -> Self {
+        let schedule = repo.schedule();
+
+        Self {
+            name: repo.name.clone(),
+            queued: matches!(schedule, Schedule::Queued(_) | Schedule::Pending(_)),
+            running: matches!(schedule, Schedule::Running | Schedule::Pending(_)),
+            state: repo.state(),
+        }
+    }
+}
+
+/// Read-only endpoints served along with the webhooks.
+pub fn router(registry: Arc<Registry>, queue: Queue) -> Router {
+    Router::new()
+        .route("/api/repos", get(list))
+        .route("/api/repos/{name}", get(show))
+        .with_state((registry, queue))
+}
+
+/// Endpoints changing deployments, which must never be reachable from the outside.
+fn control(registry: Arc<Registry>, queue: Queue) -> Router {
+    Router::new()
+        .route("/api/repos/{name}/trigger", post(trigger))
+        .route("/api/repos/{name}/history", get(history))
+        .route("/api/repos/{name}/history/{id}/rollback", post(rollback))
+        .route("/api/repos/{name}/release", post(release))
+        .route("/api/repos/{name}/deploy", post(deploy))
+        .with_state((registry, queue))
+}
+
+pub fn serve_control(
+    addr: String,
+    running: CancellationToken,
+    queue: Queue,
+    registry: Arc<Registry>,
+) -> impl Future<Output = Result<()>> + use<> {
+    let app = control(registry, queue);
+
+    async move {
+        let listener = tokio::net::TcpListener::bind(&addr)
+            .await
+            .with_context(|| format!("Failed to listen for control requests: {}", addr))?;
+        axum::serve(listener, app)
+            .with_graceful_shutdown(running.cancelled_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn list(State((registry, _)): State<(Arc<Registry>, Queue)>) -> Json<Vec<RepoStatus>> {
+    Json(
+        registry
+            .repos()
+            .iter()
+            .map(|repo| RepoStatus::of(repo))
+            .collect(),
+    )
+}
+
+async fn show(
+    State((registry, _)): State<(Arc<Registry>, Queue)>,
+    Path(name): Path<String>,
+) -> Result<Json<RepoStatus>, (StatusCode, &'static str)> {
+    let repo = registry
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, "Repository not found"))?;
+
+    Ok(Json(RepoStatus::of(&repo)))
+}
+
+async fn trigger(
+    State((registry, queue)): State<(Arc<Registry>, Queue)>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let repo = registry
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, "Repository not found"))?;
+
+    debug!("Trigger update manually");
+    queue.trigger(&repo, Trigger::Manual);
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn history(
+    State((registry, _)): State<(Arc<Registry>, Queue)>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<history::Entry>>, (StatusCode, &'static str)> {
+    let repo = registry
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, "Repository not found"))?;
+
+    let entries = repo.history().await.map_err(|err| {
+        error!("Failed to read history of {}: {:#}", name, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read history")
+    })?;
+
+    Ok(Json(entries))
+}
+
+async fn rollback(
+    State((registry, queue)): State<(Arc<Registry>, Queue)>,
+    Path((name, id)): Path<(String, u64)>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let repo = registry
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, "Repository not found"))?;
+
+    let entries = repo.history().await.map_err(|err| {
+        error!("Failed to read history of {}: {:#}", name, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read history")
+    })?;
+
+    let entry = entries
+        .into_iter()
+        .find(|entry| entry.id == id)
+        .ok_or((StatusCode::NOT_FOUND, "History entry not found"))?;
+
+    repo.pin(&entry.new).await.map_err(|err| {
+        error!("Failed to pin {}: {:#}", name, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to pin commit")
+    })?;
+
+    debug!("Rolling back to {}", entry.new);
+    queue.trigger(&repo, Trigger::Rollback);
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn release(
+    State((registry, queue)): State<(Arc<Registry>, Queue)>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let repo = registry
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, "Repository not found"))?;
+
+    repo.release().await.map_err(|err| {
+        error!("Failed to release pin of {}: {:#}", name, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to release pin")
+    })?;
+
+    queue.trigger(&repo, Trigger::Manual);
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn deploy(
+    State((registry, queue)): State<(Arc<Registry>, Queue)>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let repo = registry
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, "Repository not found"))?;
+
+    debug!("Deploying outside of deploy windows");
+    queue.trigger(&repo, Trigger::Deploy);
+
+    Ok(StatusCode::ACCEPTED)
+}
@@ -0,0 +1,155 @@
+use crate::config::{Config, Credentials, Secret, Webhook};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Validates the config directory and prints all problems found.
+///
+/// Returns `true` if the config is free of problems.
+pub async fn check(path: &Path) -> Result<bool> {
+    let mut problems: Vec<(PathBuf, String)> = Vec::new();
+    let mut paths: HashMap<PathBuf, String> = HashMap::new();
+
+    for (name, file) in Config::files(path).await? {
+        let config = match Config::load_config(&file).await {
+            Ok(config) => config,
+            Err(err) => {
+                // The outermost context only repeats the file name printed along with the problem
+                let causes: Vec<_> = err.chain().skip(1).map(ToString::to_string).collect();
+                problems.push((file, causes.join(": ")));
+                continue;
+            }
+        };
+
+        for problem in check_config(&config).await {
+            problems.push((file.clone(), problem));
+        }
+
+        if let Some(other) = paths.insert(config.path.clone(), name.clone()) {
+            problems.push((
+                file,
+                format!(
+                    "Path {} is also used by repository {}",
+                    config.path.display(),
+                    other
+                ),
+            ));
+        }
+    }
+
+    for (file, problem) in &problems {
+        println!("{}: {}", file.display(), problem);
+    }
+
+    if problems.is_empty() {
+        println!("Config is valid");
+    } else {
+        println!("Found {} problem(s)", problems.len());
+    }
+
+    Ok(problems.is_empty())
+}
+
+async fn check_config(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.interval.is_none() && config.schedule.is_empty() && config.webhook.is_none() {
+        problems.push(
+            "Neither interval, schedule nor webhook configured, repository is never updated"
+                .to_owned(),
+        );
+    }
+
+    if let Err(err) = check_remote_url(&config.remote_url) {
+        problems.push(format!(
+            "Invalid remote URL '{}': {}",
+            config.remote_url, err
+        ));
+    }
+
+    if config.lfs && config.lfs_url.is_none() {
+        if let Err(err) = crate::lfs::endpoint(&config.remote_url) {
+            problems.push(format!("{:#}, lfs_url required", err));
+        }
+    }
+
+    if let Some(ref verify) = config.verify {
+        if let Err(err) = crate::verify::Keys::load(verify).await {
+            problems.push(format!("{:#}", err));
+        }
+    }
+
+    for secret in secrets(config) {
+        if let Err(err) = secret.load().await {
+            problems.push(format!("{:#}", err));
+        }
+    }
+
+    problems
+}
+
+fn check_remote_url(remote_url: &str) -> Result<(), String> {
+    // Full URLs like https://host/repo.git or ssh://user@host/repo.git
+    if remote_url.contains("://") {
+        let url = url::Url::parse(remote_url).map_err(|err| err.to_string())?;
+
+        return match url.scheme() {
+            "http" | "https" | "ssh" | "git" | "file" => Ok(()),
+            scheme => Err(format!("Unsupported scheme: {}", scheme)),
+        };
+    }
+
+    // SCP-like syntax like user@host:repo.git
+    if let Some((host, path)) = remote_url.split_once(':') {
+        if !host.contains('/') {
+            let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+            if host.is_empty() {
+                return Err("Missing host".to_owned());
+            }
+            if path.is_empty() {
+                return Err("Missing path".to_owned());
+            }
+
+            return Ok(());
+        }
+    }
+
+    // Everything else is a local path
+    if !Path::new(remote_url).exists() {
+        return Err("Neither a URL nor an existing local path".to_owned());
+    }
+
+    Ok(())
+}
+
+fn secrets(config: &Config) -> Vec<&Secret> {
+    let mut secrets = Vec::new();
+
+    for credentials in config
+        .credentials
+        .iter()
+        .chain(config.submodule_credentials.values())
+    {
+        match credentials {
+            Credentials::Password(password) => {
+                secrets.push(&password.password);
+            }
+            Credentials::Ssh(ssh) => {
+                secrets.push(&ssh.private_key);
+                secrets.extend(ssh.passphrase.as_ref());
+            }
+        }
+    }
+
+    match &config.webhook {
+        None | Some(Webhook::Plain(_)) => {}
+        Some(Webhook::GitHub(github)) => {
+            secrets.extend(github.secret.as_ref());
+        }
+        Some(Webhook::GitLab(gitlab)) => {
+            secrets.extend(gitlab.token.as_ref());
+        }
+    }
+
+    secrets
+}
@@ -0,0 +1,98 @@
+//! Commands controlling a running instance through the HTTP API.
+
+use crate::history::{Entry, HookResult};
+use anyhow::{Context, Result};
+
+pub struct Client {
+    base: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(listen: &str) -> Self {
+        Self {
+            base: format!("http://{}/api/repos", listen),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Prints the history of the repository, oldest first.
+    pub async fn history(&self, repo: &str) -> Result<()> {
+        let response = self
+            .send(self.http.get(format!("{}/{}/history", self.base, repo)))
+            .await?;
+        let entries: Vec<Entry> = response.json().await.context("Invalid history response")?;
+
+        for entry in entries {
+            println!(
+                "{:>4}  {}  {} -> {}  {:<8}  {:<7}  {:.1}s{}",
+                entry.id,
+                humantime::format_rfc3339_seconds(entry.time),
+                entry.old.as_deref().map_or("-", short),
+                short(&entry.new),
+                entry.trigger,
+                match entry.hook {
+                    Some(HookResult::Success) => "success",
+                    Some(HookResult::Failure) => "failure",
+                    None => "-",
+                },
+                entry.duration,
+                entry
+                    .tag
+                    .map(|tag| format!("  {}", tag))
+                    .unwrap_or_default(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks out the commit of the history entry and pins it until released.
+    pub async fn rollback(&self, repo: &str, id: u64) -> Result<()> {
+        self.send(
+            self.http
+                .post(format!("{}/{}/history/{}/rollback", self.base, repo, id)),
+        )
+        .await?;
+
+        println!("Rolling back {} to entry {}", repo, id);
+        Ok(())
+    }
+
+    /// Removes the pin of a rollback so the repository follows the remote again.
+    pub async fn release(&self, repo: &str) -> Result<()> {
+        self.send(self.http.post(format!("{}/{}/release", self.base, repo)))
+            .await?;
+
+        println!("Released {}", repo);
+        Ok(())
+    }
+
+    /// Checks out pending changes immediately, even outside of the deploy windows.
+    pub async fn deploy(&self, repo: &str) -> Result<()> {
+        self.send(self.http.post(format!("{}/{}/deploy", self.base, repo)))
+            .await?;
+
+        println!("Deploying {}", repo);
+        Ok(())
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.base))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            anyhow::bail!("Request failed with {}: {}", status, message);
+        }
+
+        Ok(response)
+    }
+}
+
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(8)]
+}
@@ -1,10 +1,13 @@
+use crate::sparse;
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(untagged)]
 pub enum Secret {
     Literal(String),
@@ -22,7 +25,7 @@ impl Secret {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct SshCredentials {
     pub username: String,
 
@@ -32,35 +35,35 @@ pub struct SshCredentials {
     pub passphrase: Option<Secret>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct PasswordCredentials {
     pub username: String,
     pub password: Secret,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(untagged)]
 pub enum Credentials {
     Ssh(SshCredentials),
     Password(PasswordCredentials),
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct PlainWebhook {}
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct GitHubWebhook {
     pub secret: Option<Secret>,
     pub check_branch: Option<bool>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct GitLabWebhook {
     pub token: Option<Secret>,
     pub check_branch: Option<bool>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(tag = "provider", rename_all = "lowercase")]
 pub enum Webhook {
     Plain(PlainWebhook),
@@ -68,30 +71,391 @@ pub enum Webhook {
     GitLab(GitLabWebhook),
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Interval {
     #[serde(with = "serde_humantime")]
     pub interval: Duration,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// Selects the tag to follow, the highest matching tag wins.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum TagSelector {
+    /// Tags with a semantic version matching the requirement, optionally prefixed with `v`
+    Semver { semver: semver::VersionReq },
+
+    /// Tags matching a glob pattern like `v1.*` or a single tag name
+    Pattern(String),
+}
+
+impl TagSelector {
+    pub fn matches(&self, tag: &str) -> bool {
+        match self {
+            TagSelector::Semver { semver } => {
+                version(tag).is_some_and(|version| semver.matches(&version))
+            }
+            TagSelector::Pattern(pattern) => {
+                globset::Glob::new(pattern).is_ok_and(|glob| glob.compile_matcher().is_match(tag))
+            }
+        }
+    }
+
+    /// Picks the highest matching tag.
+    ///
+    /// Tags are ordered by their semantic version if any, tags without one come first.
+    pub fn select<'a>(&self, tags: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        tags.into_iter()
+            .filter(|tag| self.matches(tag))
+            .max_by_key(|tag| (version(tag), *tag))
+    }
+}
+
+impl std::fmt::Display for TagSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TagSelector::Semver { semver } => write!(f, "semver {}", semver),
+            TagSelector::Pattern(pattern) => write!(f, "{}", pattern),
+        }
+    }
+}
+
+fn version(tag: &str) -> Option<semver::Version> {
+    semver::Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+/// Slows down periodic checks of a repository failing repeatedly.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Backoff {
+    /// Longest time between two checks, the interval doubles on each failure until reaching it
+    #[serde(with = "serde_humantime", default = "Backoff::default_max")]
+    pub max: Duration,
+}
+
+impl Backoff {
+    fn default_max() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    /// Number of intervals to skip after the given number of consecutive failures.
+    pub fn skips(&self, interval: Duration, failures: u32) -> u32 {
+        let limit = (self.max.as_secs_f64() / interval.as_secs_f64()) as u32;
+        let factor = 2u32
+            .saturating_pow(failures.saturating_sub(1))
+            .min(limit.max(1));
+        factor - 1
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            max: Self::default_max(),
+        }
+    }
+}
+
+/// A recurring time range in which changes may be checked out.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum DeployWindow {
+    /// Every minute matching the pattern
+    Cron {
+        cron: Box<croner::Cron>,
+        timezone: Option<Tz>,
+    },
+
+    /// From the start to the end time on the given days, or on every day if empty
+    Range {
+        #[serde(default)]
+        days: Vec<Weekday>,
+        from: NaiveTime,
+        to: NaiveTime,
+        timezone: Option<Tz>,
+    },
+}
+
+impl DeployWindow {
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        match self {
+            DeployWindow::Cron { cron, timezone } => {
+                // Patterns without seconds only match the start of each minute
+                let time = local_time(time, timezone.as_ref())
+                    .with_second(0)
+                    .and_then(|time| time.with_nanosecond(0));
+                time.is_some_and(|time| cron.is_time_matching(&time).unwrap_or(false))
+            }
+            DeployWindow::Range {
+                days,
+                from,
+                to,
+                timezone,
+            } => {
+                let time = local_time(time, timezone.as_ref());
+                let on = |day: Weekday| days.is_empty() || days.contains(&day);
+
+                if from < to {
+                    on(time.weekday()) && *from <= time.time() && time.time() < *to
+                } else {
+                    // Ranges crossing midnight belong to the day they start
+                    (on(time.weekday()) && *from <= time.time())
+                        || (on(time.weekday().pred()) && time.time() < *to)
+                }
+            }
+        }
+    }
+}
+
+fn local_time(time: DateTime<Utc>, timezone: Option<&Tz>) -> NaiveDateTime {
+    match timezone {
+        Some(timezone) => time.with_timezone(timezone).naive_local(),
+        None => time.with_timezone(&Local).naive_local(),
+    }
+}
+
+/// Keys trusted to sign commits before they are checked out.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Verify {
+    /// SSH public keys in OpenSSH format
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+
+    /// File listing SSH keys in the allowed signers format of `ssh-keygen`
+    pub allowed_signers: Option<PathBuf>,
+
+    /// Files containing OpenPGP public keys
+    #[serde(default)]
+    pub openpgp_keys: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Releases {
+    /// Number of releases kept including the current one
+    #[serde(default = "Releases::default_keep")]
+    pub keep: usize,
+}
+
+impl Releases {
+    fn default_keep() -> usize {
+        5
+    }
+}
+
+/// Whether submodules are checked out along with the repository.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Submodules {
+    #[default]
+    Disabled,
+    Enabled,
+
+    /// Including submodules of submodules
+    Recursive,
+}
+
+impl<'de> Deserialize<'de> for Submodules {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Enabled(bool),
+            Mode(String),
+        }
+
+        match Value::deserialize(deserializer)? {
+            Value::Enabled(false) => Ok(Submodules::Disabled),
+            Value::Enabled(true) => Ok(Submodules::Enabled),
+            Value::Mode(mode) if mode == "recursive" => Ok(Submodules::Recursive),
+            Value::Mode(mode) => Err(serde::de::Error::custom(format!(
+                "invalid submodules mode: {}",
+                mode
+            ))),
+        }
+    }
+}
+
+/// A script executed on changes, optionally only if files matching the paths have changed.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Hook {
+    pub script: String,
+
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+impl Hook {
+    /// Checks if the hook applies to a change of the given files.
+    pub fn matches(&self, files: &[PathBuf]) -> bool {
+        if self.paths.is_empty() {
+            return true;
+        }
+
+        let filter = sparse::Filter::new(&self.paths).expect("validated config");
+        files.iter().any(|file| filter.matches(file))
+    }
+}
+
+/// Accepts a single script or a list of hooks.
+fn deserialize_hooks<'de, D>(deserializer: D) -> Result<Vec<Hook>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Script(String),
+        Hooks(Vec<Hook>),
+    }
+
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(Value::Script(script)) => vec![Hook {
+            script,
+            paths: Vec::new(),
+        }],
+        Some(Value::Hooks(hooks)) => hooks,
+    })
+}
+
+/// What to do if the `on_change` script fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    /// Report the failure only
+    #[default]
+    Ignore,
+
+    /// Run the script again on the next update even if there are no new changes
+    Retry,
+}
+
+/// What to do with files changed by hand in the working tree before checking out a change.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalChanges {
+    /// Overwrite the changes
+    #[default]
+    Discard,
+
+    /// Skip the update until the changes are cleaned up
+    Refuse,
+
+    /// Save the changes as stash entry before overwriting them
+    Stash,
+}
+
+fn deserialize_duration_opt<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    serde_humantime::De::<Option<Duration>>::deserialize(deserializer)
+        .map(serde_humantime::De::into_inner)
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Config {
     pub path: PathBuf,
 
     pub remote_url: String,
-    pub remote_branch: String,
+    pub remote_branch: Option<String>,
+    pub remote_tag: Option<TagSelector>,
+    pub remote_ref: Option<String>,
 
-    pub on_change: Option<String>,
+    /// Commit checked out instead of the remote head
+    pub pin: Option<String>,
+
+    pub pre_change: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_hooks")]
+    pub on_change: Vec<Hook>,
+
+    #[serde(default, deserialize_with = "deserialize_duration_opt")]
+    pub on_change_timeout: Option<Duration>,
+
+    #[serde(default)]
+    pub on_change_failure: HookFailure,
+
+    #[serde(default)]
+    pub rollback_on_failure: bool,
+
+    #[serde(default)]
+    pub local_changes: LocalChanges,
 
     pub credentials: Option<Credentials>,
 
+    /// Number of commits fetched from the remote, the full history is fetched if missing
+    pub depth: Option<u32>,
+
+    pub verify: Option<Verify>,
+
+    /// Patterns limiting the checkout to a subset of paths
+    #[serde(default)]
+    pub paths: Vec<String>,
+
+    #[serde(default)]
+    pub lfs: bool,
+
+    /// LFS endpoint, derived from the remote URL if missing
+    pub lfs_url: Option<String>,
+
+    #[serde(default)]
+    pub submodules: Submodules,
+
+    /// Credentials for submodules by submodule name, falling back to the repository credentials
+    #[serde(default)]
+    pub submodule_credentials: HashMap<String, Credentials>,
+
+    pub releases: Option<Releases>,
+
     pub interval: Option<Interval>,
+
+    /// Cron patterns of the times to check for updates instead of an interval
+    #[serde(default)]
+    pub schedule: Vec<croner::Cron>,
+
+    /// Random delay of up to the given duration added to each periodic check
+    #[serde(default, deserialize_with = "deserialize_duration_opt")]
+    pub jitter: Option<Duration>,
+
     pub webhook: Option<Webhook>,
+
+    #[serde(default)]
+    pub backoff: Backoff,
+
+    /// Time ranges in which changes are checked out, any time if empty
+    #[serde(default)]
+    pub deploy_windows: Vec<DeployWindow>,
 }
 
 impl Config {
     pub async fn load(path: &Path) -> Result<HashMap<String, Self>> {
         let mut configs = HashMap::new();
+        let mut paths: HashMap<PathBuf, String> = HashMap::new();
+
+        for (name, path) in Self::files(path).await? {
+            let config = Self::load_config(&path)
+                .await
+                .with_context(|| format!("Failed to load config file: {}", path.display()))?;
+
+            // Updates of repositories sharing a path would run concurrently on the same tree
+            if let Some(other) = paths.insert(config.path.clone(), name.clone()) {
+                anyhow::bail!(
+                    "Repositories {} and {} use the same path: {}",
+                    other,
+                    name,
+                    config.path.display()
+                );
+            }
+
+            configs.insert(name, config);
+        }
+
+        Ok(configs)
+    }
+
+    /// Lists all config files in the config directory along with the repository names.
+    pub async fn files(path: &Path) -> Result<Vec<(String, PathBuf)>> {
+        let mut files = Vec::new();
 
         if !path.exists() {
             anyhow::bail!("Config directory does not exist: {}", path.display());
@@ -107,30 +471,315 @@ impl Config {
             // TODO: Can we do this better?
             let name = path.file_name().unwrap().to_str().unwrap().to_owned();
 
-            let config = Self::load_config(&path)
-                .await
-                .with_context(|| format!("Failed to load config file: {}", path.display()))?;
+            // Skip hidden files like editor swap files
+            if name.starts_with('.') {
+                continue;
+            }
 
-            configs.insert(name, config);
+            files.push((name, path));
         }
 
-        Ok(configs)
+        files.sort();
+
+        Ok(files)
     }
 
-    async fn load_config(path: &Path) -> Result<Self> {
+    pub async fn load_config(path: &Path) -> Result<Self> {
         // FIXME: Specify interval as string (i.e. "5m")
 
         let input = tokio::fs::read_to_string(&path)
             .await
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
 
-        let config = serde_yaml::from_str(&input)
+        let config: Self = serde_yaml::from_str(&input)
             .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
 
+        config
+            .validate()
+            .with_context(|| format!("Invalid config file: {}", path.display()))?;
+
         Ok(config)
     }
 
-    pub fn remote_ref(&self) -> String {
-        format!("refs/heads/{}", self.remote_branch)
+    fn validate(&self) -> Result<()> {
+        let targets = [
+            self.remote_branch.is_some(),
+            self.remote_tag.is_some(),
+            self.remote_ref.is_some(),
+        ];
+        match targets.into_iter().filter(|target| *target).count() {
+            0 => anyhow::bail!("One of remote_branch, remote_tag or remote_ref is required"),
+            1 => {}
+            _ => anyhow::bail!("Only one of remote_branch, remote_tag or remote_ref is allowed"),
+        }
+
+        if let Some(TagSelector::Pattern(ref pattern)) = self.remote_tag {
+            globset::Glob::new(pattern)
+                .with_context(|| format!("Invalid tag pattern: {}", pattern))?;
+        }
+
+        if let Some(ref remote_ref) = self.remote_ref {
+            if !remote_ref.starts_with("refs/") {
+                anyhow::bail!("Not a full ref: {}", remote_ref);
+            }
+        }
+
+        sparse::Filter::new(&self.paths)?;
+        for hook in &self.on_change {
+            sparse::Filter::new(&hook.paths)?;
+        }
+
+        if self.submodules != Submodules::Disabled && self.releases.is_some() {
+            anyhow::bail!("Submodules are not supported together with releases");
+        }
+
+        if self.interval.is_some() && !self.schedule.is_empty() {
+            anyhow::bail!("Only one of interval or schedule is allowed");
+        }
+
+        for window in &self.deploy_windows {
+            if let DeployWindow::Range { from, to, .. } = window {
+                if from == to {
+                    anyhow::bail!("Empty deploy window: {} to {}", from, to);
+                }
+            }
+        }
+
+        if self.rollback_on_failure && self.on_change_failure == HookFailure::Retry {
+            anyhow::bail!("A failed script can not be retried together with rollback_on_failure");
+        }
+
+        if self.local_changes != LocalChanges::Discard && self.releases.is_some() {
+            anyhow::bail!("Local changes can not be kept together with releases");
+        }
+
+        // The HTTP based LFS API can not authenticate using SSH keys
+        if self.lfs && self.lfs_url.is_none() {
+            if let Some(Credentials::Ssh(_)) = self.credentials {
+                anyhow::bail!("LFS with SSH credentials requires an explicit lfs_url");
+            }
+        }
+
+        if self.depth == Some(0) {
+            anyhow::bail!("Depth must be at least 1");
+        }
+
+        if let Some(ref pin) = self.pin {
+            if pin.len() != 40 || !pin.chars().all(|c| c.is_ascii_hexdigit()) {
+                anyhow::bail!("Pin is not a full commit id: {}", pin);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Path of the git repository, which is kept next to the release directories if enabled.
+    pub fn repository_path(&self) -> PathBuf {
+        match self.releases {
+            Some(_) => self.path.join("repo"),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The first scheduled check after the given time.
+    pub fn next_scheduled(&self, after: &DateTime<Local>) -> Option<DateTime<Local>> {
+        self.schedule
+            .iter()
+            .filter_map(|cron| cron.find_next_occurrence(after, false).ok())
+            .min()
+    }
+
+    /// Checks if changes may be checked out at the given time.
+    pub fn in_deploy_window(&self, time: DateTime<Utc>) -> bool {
+        self.deploy_windows.is_empty()
+            || self
+                .deploy_windows
+                .iter()
+                .any(|window| window.contains(time))
+    }
+
+    /// Approximate time between two periodic checks.
+    pub fn period(&self) -> Option<Duration> {
+        if let Some(ref interval) = self.interval {
+            return Some(interval.interval);
+        }
+
+        let next = self.next_scheduled(&Local::now())?;
+        let following = self.next_scheduled(&next)?;
+        (following - next).to_std().ok()
+    }
+
+    /// Path of the git directory.
+    pub fn git_dir(&self) -> PathBuf {
+        match self.releases {
+            Some(_) => self.repository_path(),
+            None => self.path.join(".git"),
+        }
+    }
+
+    /// Filter limiting the checkout if paths are configured.
+    pub fn filter(&self) -> Option<sparse::Filter> {
+        if self.paths.is_empty() {
+            return None;
+        }
+
+        Some(sparse::Filter::new(&self.paths).expect("validated config"))
+    }
+
+    /// Checks if the remote ref is the followed ref, branch or a followed tag.
+    pub fn follows(&self, reference: &str) -> bool {
+        if let Some(ref selector) = self.remote_tag {
+            return reference
+                .strip_prefix("refs/tags/")
+                .is_some_and(|tag| selector.matches(tag));
+        }
+
+        if let Some(ref remote_ref) = self.remote_ref {
+            return reference == remote_ref;
+        }
+
+        reference.strip_prefix("refs/heads/") == self.remote_branch.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn semver(requirement: &str) -> TagSelector {
+        TagSelector::Semver {
+            semver: requirement.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn select_semver() {
+        let tags = ["v1.2.0", "1.10.0", "v1.9.3", "v2.0.0", "latest"];
+
+        assert_eq!(semver("^1").select(tags), Some("1.10.0"));
+        assert_eq!(semver("~1.9").select(tags), Some("v1.9.3"));
+        assert_eq!(semver("*").select(tags), Some("v2.0.0"));
+        assert_eq!(semver(">=3").select(tags), None);
+    }
+
+    #[test]
+    fn select_semver_prerelease() {
+        let tags = ["v1.0.0", "v1.1.0-rc.1"];
+
+        assert_eq!(semver("^1").select(tags), Some("v1.0.0"));
+        assert_eq!(semver(">=1.1.0-rc.1").select(tags), Some("v1.1.0-rc.1"));
+    }
+
+    #[test]
+    fn select_pattern() {
+        let pattern = TagSelector::Pattern("release-*".to_owned());
+
+        // Tags without a semantic version sort before versioned ones and by name among each other
+        assert_eq!(
+            pattern.select(["release-b", "release-a", "other"]),
+            Some("release-b")
+        );
+        assert_eq!(
+            TagSelector::Pattern("*".to_owned()).select(["zzz", "v1.0.0", "v0.9.0", "aaa"]),
+            Some("v1.0.0")
+        );
+
+        assert_eq!(
+            TagSelector::Pattern("v1.0.0".to_owned()).select(["v1.0.0", "v1.0.1"]),
+            Some("v1.0.0")
+        );
+        assert_eq!(pattern.select(["other"]), None);
+    }
+
+    #[test]
+    fn backoff_skips() {
+        let backoff = Backoff::default();
+        let interval = Duration::from_secs(60);
+
+        let skips: Vec<_> = (0..=7)
+            .map(|failures| backoff.skips(interval, failures))
+            .collect();
+        assert_eq!(skips, [0, 0, 1, 3, 7, 15, 31, 59]);
+    }
+
+    #[test]
+    fn backoff_capped() {
+        let backoff = Backoff {
+            max: Duration::from_secs(60 * 60),
+        };
+
+        // The interval between checks never exceeds the maximum
+        assert_eq!(backoff.skips(Duration::from_secs(60), 100), 59);
+        assert_eq!(backoff.skips(Duration::from_secs(60), u32::MAX), 59);
+        assert_eq!(backoff.skips(Duration::from_secs(7 * 60), 10), 7);
+
+        // Intervals longer than the maximum are never skipped
+        assert_eq!(backoff.skips(Duration::from_secs(2 * 60 * 60), 10), 0);
+    }
+
+    fn window(input: &str) -> DeployWindow {
+        serde_yaml::from_str(input).unwrap()
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn window_range() {
+        let window = window("{ days: [Mon], from: '09:00', to: '17:00', timezone: UTC }");
+
+        assert!(window.contains(utc("2026-10-19T09:00:00Z")));
+        assert!(window.contains(utc("2026-10-19T16:59:59Z")));
+        assert!(!window.contains(utc("2026-10-19T17:00:00Z")));
+        assert!(!window.contains(utc("2026-10-19T08:59:59Z")));
+        assert!(!window.contains(utc("2026-10-20T12:00:00Z")));
+    }
+
+    #[test]
+    fn window_range_midnight() {
+        // Berlin is two hours ahead of UTC in October
+        let window =
+            window("{ days: [Sat, Sun], from: '22:00', to: '04:00', timezone: Europe/Berlin }");
+
+        // Saturday evening and the following night
+        assert!(window.contains(utc("2026-10-17T20:00:00Z")));
+        assert!(window.contains(utc("2026-10-17T23:00:00Z")));
+        assert!(!window.contains(utc("2026-10-17T19:59:59Z")));
+
+        // Sunday night belongs to Sunday although it ends on Monday
+        assert!(window.contains(utc("2026-10-18T23:00:00Z")));
+        assert!(!window.contains(utc("2026-10-19T02:00:00Z")));
+
+        // Friday night belongs to Friday
+        assert!(!window.contains(utc("2026-10-16T23:00:00Z")));
+    }
+
+    #[test]
+    fn window_range_every_day() {
+        let window = window("{ from: '23:00', to: '01:00', timezone: UTC }");
+
+        assert!(window.contains(utc("2026-10-20T23:30:00Z")));
+        assert!(window.contains(utc("2026-10-21T00:30:00Z")));
+        assert!(!window.contains(utc("2026-10-21T01:00:00Z")));
+    }
+
+    #[test]
+    fn window_cron() {
+        let window = window("{ cron: '* 12 * * Mon-Fri', timezone: UTC }");
+
+        assert!(window.contains(utc("2026-10-19T12:00:00Z")));
+        assert!(window.contains(utc("2026-10-19T12:59:59Z")));
+        assert!(!window.contains(utc("2026-10-19T13:00:00Z")));
+        assert!(!window.contains(utc("2026-10-17T12:30:00Z")));
+    }
+
+    #[test]
+    fn window_cron_timezone() {
+        // New York is four hours behind UTC in October
+        let window = window("{ cron: '* 12 * * *', timezone: America/New_York }");
+
+        assert!(window.contains(utc("2026-10-19T16:30:00Z")));
+        assert!(!window.contains(utc("2026-10-19T12:30:00Z")));
     }
 }
@@ -0,0 +1,163 @@
+//! Persistent record of the changes checked out, kept inside the git directory.
+
+use crate::config::Config;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+
+const DIR: &str = "pullomatic";
+const HISTORY_FILE: &str = "history.jsonl";
+const PIN_FILE: &str = "pin";
+const REJECTED_FILE: &str = "rejected";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookResult {
+    Success,
+    Failure,
+}
+
+/// A change checked out by an update or a rollback.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Sequence number of the entry, starting at 1
+    pub id: u64,
+
+    #[serde(with = "crate::timestamp")]
+    pub time: SystemTime,
+
+    pub old: Option<String>,
+    pub new: String,
+    pub tag: Option<String>,
+
+    pub trigger: String,
+
+    /// Outcome of the `on_change` scripts, if any were executed
+    pub hook: Option<HookResult>,
+
+    /// Duration of the update in seconds
+    pub duration: f64,
+}
+
+/// The history, the pin and the rejected commit of a repository stored on disk.
+#[derive(Debug)]
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            dir: config.git_dir().join(DIR),
+        }
+    }
+
+    /// Lists all entries, oldest first.
+    pub async fn entries(&self) -> Result<Vec<Entry>> {
+        let path = self.dir.join(HISTORY_FILE);
+
+        let input = match tokio::fs::read_to_string(&path).await {
+            Ok(input) => input,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read history: {}", path.display()))
+            }
+        };
+
+        input
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Invalid history entry in {}", path.display()))
+            })
+            .collect()
+    }
+
+    /// Appends an entry, numbering it after the last one.
+    pub async fn append(&self, mut entry: Entry) -> Result<Entry> {
+        entry.id = self.entries().await?.last().map_or(1, |last| last.id + 1);
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let path = self.dir.join(HISTORY_FILE);
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open history: {}", path.display()))?;
+        file.write_all(line.as_bytes()).await?;
+
+        Ok(entry)
+    }
+
+    /// Commit pinned by a rollback until released.
+    pub async fn pin(&self) -> Result<Option<String>> {
+        self.read_commit(PIN_FILE).await
+    }
+
+    pub async fn set_pin(&self, pin: Option<&str>) -> Result<()> {
+        self.write_commit(PIN_FILE, pin).await
+    }
+
+    /// Commit rolled back after a failed script, skipped until the remote moves on.
+    pub async fn rejected(&self) -> Result<Option<String>> {
+        self.read_commit(REJECTED_FILE).await
+    }
+
+    pub async fn set_rejected(&self, rejected: Option<&str>) -> Result<()> {
+        self.write_commit(REJECTED_FILE, rejected).await
+    }
+
+    /// Forgets the rejected commit without awaiting, for use while git objects are held.
+    pub fn clear_rejected(&self) -> Result<()> {
+        let path = self.dir.join(REJECTED_FILE);
+
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err)
+                .with_context(|| format!("Failed to write {}: {}", REJECTED_FILE, path.display())),
+            _ => Ok(()),
+        }
+    }
+
+    async fn read_commit(&self, file: &str) -> Result<Option<String>> {
+        let path = self.dir.join(file);
+
+        match tokio::fs::read_to_string(&path).await {
+            Ok(commit) => Ok(Some(commit.trim().to_owned())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to read {}: {}", file, path.display()))
+            }
+        }
+    }
+
+    async fn write_commit(&self, file: &str, commit: Option<&str>) -> Result<()> {
+        let path = self.dir.join(file);
+
+        match commit {
+            Some(commit) => {
+                tokio::fs::create_dir_all(&self.dir).await?;
+                write_atomic(&path, commit).await
+            }
+            None => match tokio::fs::remove_file(&path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            },
+        }
+        .with_context(|| format!("Failed to write {}: {}", file, path.display()))
+    }
+}
+
+async fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let staging = path.with_extension("new");
+    tokio::fs::write(&staging, format!("{}\n", content)).await?;
+    tokio::fs::rename(&staging, path).await?;
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use crate::config::Config;
+use crate::queue::Trigger;
+use crate::repo::Change;
+use anyhow::{Context, Result};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::pin::pin;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_util::sync::CancellationToken;
+use tracing::{trace, warn};
+
+/// Environment variables describing an update passed to hook scripts.
+pub struct Env {
+    vars: Vec<(&'static str, String)>,
+
+    // Kept alive until the script has finished
+    _changed_files: NamedTempFile,
+}
+
+impl Env {
+    pub fn new(name: &str, config: &Config, trigger: Trigger, change: &Change) -> Result<Self> {
+        // Write the list of changed files, one per line
+        let mut changed_files =
+            NamedTempFile::new().context("Failed to create changed files list")?;
+        for file in &change.files {
+            changed_files.write_all(file.as_os_str().as_bytes())?;
+            changed_files.write_all(b"\n")?;
+        }
+        changed_files.flush()?;
+
+        let mut vars = vec![
+            ("PULLOMATIC_REPO", name.to_owned()),
+            (
+                "PULLOMATIC_OLD_COMMIT",
+                change.old.map(|old| old.to_string()).unwrap_or_default(),
+            ),
+            ("PULLOMATIC_NEW_COMMIT", change.new.to_string()),
+            ("PULLOMATIC_REMOTE_URL", config.remote_url.clone()),
+            (
+                "PULLOMATIC_REMOTE_BRANCH",
+                config.remote_branch.clone().unwrap_or_default(),
+            ),
+            (
+                "PULLOMATIC_REMOTE_TAG",
+                change.tag.clone().unwrap_or_default(),
+            ),
+            ("PULLOMATIC_TRIGGER", trigger.as_str().to_owned()),
+            (
+                "PULLOMATIC_CHANGED_FILES",
+                changed_files.path().display().to_string(),
+            ),
+        ];
+
+        if let Trigger::Webhook(provider) = trigger {
+            vars.push(("PULLOMATIC_WEBHOOK", provider.as_str().to_owned()));
+        }
+
+        Ok(Self {
+            vars,
+            _changed_files: changed_files,
+        })
+    }
+
+    /// Points the script to the exported tree of the new commit.
+    pub fn tree(mut self, path: &Path) -> Self {
+        self.vars
+            .push(("PULLOMATIC_NEW_TREE", path.display().to_string()));
+        self
+    }
+
+    /// Marks the script run as restoring the previous commit after a failed change.
+    pub fn rollback(mut self) -> Self {
+        self.vars.push(("PULLOMATIC_ROLLBACK", "1".to_owned()));
+        self
+    }
+}
+
+/// Time granted to a script to exit after SIGTERM before it gets killed
+const KILL_GRACE: Duration = Duration::from_secs(10);
+
+/// Why a script was terminated before it has finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    TimedOut(Duration),
+
+    /// The daemon is shutting down, so the script has not failed by itself
+    Interrupted,
+}
+
+/// A script terminated before it has finished.
+#[derive(Debug)]
+pub struct Terminated {
+    pub reason: Reason,
+
+    /// Exit status of the script, if it has exited after being killed
+    pub status: Option<ExitStatus>,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::TimedOut(timeout) => write!(
+                f,
+                "Script timed out after {}",
+                humantime::format_duration(*timeout)
+            ),
+            Reason::Interrupted => write!(f, "Script interrupted by shutdown"),
+        }
+    }
+}
+
+impl std::fmt::Display for Terminated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} ({})", self.reason, status),
+            None => write!(f, "{} (did not exit after being killed)", self.reason),
+        }
+    }
+}
+
+impl std::error::Error for Terminated {}
+
+/// Runs the script using `sh -c` inside the given directory.
+///
+/// The script runs in its own process group. If the timeout expires or the daemon is shutting
+/// down, the whole group is terminated.
+pub async fn run(
+    script: &str,
+    path: &Path,
+    env: &Env,
+    timeout: Option<Duration>,
+    running: &CancellationToken,
+) -> Result<ExitStatus> {
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(script)
+        .current_dir(path)
+        .envs(env.vars.iter().map(|(key, value)| (key, value)))
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn script")?;
+
+    let group = Pid::from_raw(
+        child
+            .id()
+            .and_then(|pid| i32::try_from(pid).ok())
+            .context("Failed to get script PID")?,
+    );
+
+    let mut stdout = BufReader::new(child.stdout.take().expect("Failed to take stdout")).lines();
+    let mut stderr = BufReader::new(child.stderr.take().expect("Failed to take stderr")).lines();
+
+    let reason = {
+        let mut wait = pin!(async {
+            loop {
+                tokio::select! {
+                    Ok(Some(line)) = stdout.next_line() => {
+                        trace!("> {}", line);
+                    }
+
+                    Ok(Some(line)) = stderr.next_line() => {
+                        trace!("! {}", line);
+                    }
+
+                    else => break,
+                }
+            }
+
+            child.wait().await.context("Failed to wait for script")
+        });
+
+        let expired = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let reason = tokio::select! {
+            status = &mut wait => {
+                return status;
+            }
+
+            _ = expired => {
+                Reason::TimedOut(timeout.unwrap_or_default())
+            }
+
+            _ = running.cancelled() => {
+                Reason::Interrupted
+            }
+        };
+
+        warn!("{}, terminating", reason);
+        let _ = killpg(group, Signal::SIGTERM);
+
+        if let Ok(status) = tokio::time::timeout(KILL_GRACE, &mut wait).await {
+            let status = Some(status?);
+            return Err(Terminated { reason, status }.into());
+        }
+
+        reason
+    };
+
+    warn!("Script still running, killing");
+    let _ = killpg(group, Signal::SIGKILL);
+
+    // Processes which left the group may still hold the pipes open, so the output is abandoned
+    drop(stdout);
+    drop(stderr);
+
+    let status = match tokio::time::timeout(KILL_GRACE, child.wait()).await {
+        Ok(status) => Some(status.context("Failed to wait for script")?),
+        Err(_) => None,
+    };
+
+    Err(Terminated { reason, status }.into())
+}
@@ -0,0 +1,528 @@
+//! Fetching of Git LFS objects using the batch API.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+const POINTER_VERSION: &[u8] = b"version https://git-lfs.github.com/spec/v1\n";
+
+/// Pointer files are small by definition
+const POINTER_MAX_SIZE: usize = 1024;
+
+const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// A pointer file in the tree referring to an LFS object.
+#[derive(Debug, Clone)]
+pub struct Pointer {
+    pub path: PathBuf,
+    pub oid: String,
+    pub size: u64,
+}
+
+impl Pointer {
+    fn parse(path: PathBuf, data: &[u8]) -> Option<Self> {
+        let data = std::str::from_utf8(data.strip_prefix(POINTER_VERSION)?).ok()?;
+
+        let mut oid = None;
+        let mut size = None;
+        for line in data.lines() {
+            match line.split_once(' ') {
+                Some(("oid", value)) => oid = value.strip_prefix("sha256:"),
+                Some(("size", value)) => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        let oid =
+            oid.filter(|oid| oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_hexdigit()))?;
+
+        Some(Self {
+            path,
+            oid: oid.to_owned(),
+            size: size?,
+        })
+    }
+}
+
+/// Lists all pointer files in the tree of the commit.
+pub fn pointers(repository: &git2::Repository, commit: git2::Oid) -> Result<Vec<Pointer>> {
+    let tree = repository.find_commit(commit)?.tree()?;
+    let odb = repository.odb()?;
+
+    let mut pointers = Vec::new();
+    let mut error = None;
+
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() != Some(git2::ObjectType::Blob) {
+            return git2::TreeWalkResult::Ok;
+        }
+
+        let result = (|| -> Result<_, git2::Error> {
+            // Check the size first to avoid loading large blobs
+            let (size, _) = odb.read_header(entry.id())?;
+            if size > POINTER_MAX_SIZE {
+                return Ok(None);
+            }
+
+            let blob = repository.find_blob(entry.id())?;
+            let path = Path::new(root).join(String::from_utf8_lossy(entry.name_bytes()).as_ref());
+            Ok(Pointer::parse(path, blob.content()))
+        })();
+
+        match result {
+            Ok(pointer) => {
+                pointers.extend(pointer);
+                git2::TreeWalkResult::Ok
+            }
+            Err(err) => {
+                error = Some(err);
+                git2::TreeWalkResult::Abort
+            }
+        }
+    })?;
+
+    if let Some(err) = error {
+        return Err(err).context("Failed to read tree");
+    }
+
+    Ok(pointers)
+}
+
+/// Local storage of LFS objects inside the git directory, using the layout of `git lfs`.
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(repository: &git2::Repository) -> Self {
+        Self {
+            dir: repository.path().join("lfs").join("objects"),
+        }
+    }
+
+    fn object(&self, oid: &str) -> PathBuf {
+        self.dir.join(&oid[0..2]).join(&oid[2..4]).join(oid)
+    }
+
+    /// Replaces the pointer files in the working tree with the content of the objects.
+    pub fn smudge(&self, worktree: &Path, pointers: &[Pointer]) -> Result<()> {
+        for pointer in pointers {
+            let target = worktree.join(&pointer.path);
+
+            // Copy next to the target first to replace the pointer atomically
+            let staging = target.with_file_name(format!(".{}.lfs", pointer.oid));
+            std::fs::copy(self.object(&pointer.oid), &staging)
+                .with_context(|| format!("Failed to copy LFS object: {}", pointer.oid))?;
+            std::fs::rename(&staging, &target)
+                .with_context(|| format!("Failed to replace LFS pointer: {}", target.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Credentials used for basic authentication against the LFS server.
+#[derive(Debug)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+struct BatchRequest<'a> {
+    operation: &'static str,
+    transfers: [&'static str; 1],
+    objects: Vec<BatchObject<'a>>,
+    hash_algo: &'static str,
+}
+
+#[derive(Serialize)]
+struct BatchObject<'a> {
+    oid: &'a str,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct BatchResponse {
+    objects: Vec<BatchResult>,
+}
+
+#[derive(Deserialize)]
+struct BatchResult {
+    oid: String,
+    actions: Option<BatchActions>,
+    error: Option<BatchError>,
+}
+
+#[derive(Deserialize)]
+struct BatchActions {
+    download: Option<BatchAction>,
+}
+
+#[derive(Deserialize)]
+struct BatchAction {
+    href: String,
+    #[serde(default)]
+    header: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct BatchError {
+    code: u16,
+    message: String,
+}
+
+/// Downloads all objects referred by the pointers which are missing in the store.
+pub async fn fetch(
+    store: &Store,
+    endpoint: &str,
+    login: Option<&Login>,
+    pointers: &[Pointer],
+) -> Result<()> {
+    let mut missing: Vec<&Pointer> = Vec::new();
+    for pointer in pointers {
+        if !tokio::fs::try_exists(store.object(&pointer.oid)).await?
+            && !missing.iter().any(|other| other.oid == pointer.oid)
+        {
+            missing.push(pointer);
+        }
+    }
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    info!("Downloading {} LFS object(s)", missing.len());
+
+    let client = reqwest::Client::new();
+
+    let request = BatchRequest {
+        operation: "download",
+        transfers: ["basic"],
+        objects: missing
+            .iter()
+            .map(|pointer| BatchObject {
+                oid: &pointer.oid,
+                size: pointer.size,
+            })
+            .collect(),
+        hash_algo: "sha256",
+    };
+
+    let mut batch = client
+        .post(format!("{}/objects/batch", endpoint.trim_end_matches('/')))
+        .header(reqwest::header::ACCEPT, MEDIA_TYPE)
+        .header(reqwest::header::CONTENT_TYPE, MEDIA_TYPE)
+        .json(&request);
+    if let Some(login) = login {
+        batch = batch.basic_auth(&login.username, Some(&login.password));
+    }
+
+    let response: BatchResponse = batch
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("LFS batch request failed: {}", endpoint))?
+        .json()
+        .await
+        .context("Invalid LFS batch response")?;
+
+    for result in response.objects {
+        if let Some(error) = result.error {
+            anyhow::bail!(
+                "LFS object {} not available: {} ({})",
+                result.oid,
+                error.message,
+                error.code
+            );
+        }
+
+        let Some(pointer) = missing.iter().find(|pointer| pointer.oid == result.oid) else {
+            continue;
+        };
+
+        let Some(download) = result.actions.and_then(|actions| actions.download) else {
+            // Objects without actions are considered present by the server protocol
+            continue;
+        };
+
+        download_object(&client, store, pointer, &download)
+            .await
+            .with_context(|| format!("Failed to download LFS object: {}", pointer.oid))?;
+    }
+
+    Ok(())
+}
+
+async fn download_object(
+    client: &reqwest::Client,
+    store: &Store,
+    pointer: &Pointer,
+    download: &BatchAction,
+) -> Result<()> {
+    debug!("Downloading LFS object {}", pointer.oid);
+
+    let mut request = client.get(&download.href);
+    for (name, value) in &download.header {
+        request = request.header(name, value);
+    }
+
+    let mut response = request.send().await?.error_for_status()?;
+
+    let path = store.object(&pointer.oid);
+    let dir = path.parent().expect("object path has parent");
+    tokio::fs::create_dir_all(dir).await?;
+
+    // Objects are only moved into the store after their content has been verified
+    let staging = tempfile::NamedTempFile::new_in(dir)?;
+    let mut file = tokio::fs::File::from_std(staging.reopen()?);
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = response.chunk().await? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    if size != pointer.size || hex::encode(hasher.finalize()) != pointer.oid {
+        anyhow::bail!("Downloaded content does not match object");
+    }
+
+    staging.persist(&path)?;
+
+    Ok(())
+}
+
+/// Derives the LFS endpoint from the remote URL like `git lfs` does.
+pub fn endpoint(remote_url: &str) -> Result<String> {
+    let (host, path) = if remote_url.contains("://") {
+        let url = url::Url::parse(remote_url)?;
+        match url.scheme() {
+            "http" | "https" => {
+                return Ok(suffix(url.as_str()));
+            }
+            "ssh" | "git" => (
+                url.host_str().unwrap_or_default().to_owned(),
+                url.path().to_owned(),
+            ),
+            scheme => anyhow::bail!("Can not derive LFS endpoint for scheme {}", scheme),
+        }
+    } else {
+        // SCP-like syntax like user@host:repo.git
+        let Some((host, path)) = remote_url.split_once(':') else {
+            anyhow::bail!("Can not derive LFS endpoint for local path");
+        };
+        let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+        (
+            host.to_owned(),
+            format!("/{}", path.trim_start_matches('/')),
+        )
+    };
+
+    Ok(suffix(&format!("https://{}{}", host, path)))
+}
+
+fn suffix(url: &str) -> String {
+    let url = url.trim_end_matches('/');
+    if url.ends_with(".git") {
+        format!("{}/info/lfs", url)
+    } else {
+        format!("{}.git/info/lfs", url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path as UrlPath, State};
+    use axum::routing::{get, post};
+    use axum::Json;
+    use std::sync::Arc;
+
+    const CONTENT: &[u8] = b"large file content\n";
+
+    fn oid(content: &[u8]) -> String {
+        hex::encode(Sha256::digest(content))
+    }
+
+    fn pointer(content: &[u8]) -> Pointer {
+        Pointer {
+            path: PathBuf::from("file.bin"),
+            oid: oid(content),
+            size: content.len() as u64,
+        }
+    }
+
+    /// Serves the given content for every object requested through the batch API.
+    async fn server(content: &'static [u8]) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!(
+            "http://{}/repo.git/info/lfs",
+            listener.local_addr().unwrap()
+        );
+
+        let batch = |State(endpoint): State<Arc<String>>,
+                     Json(request): Json<serde_json::Value>| async move {
+            let objects: Vec<_> = request["objects"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|object| {
+                    let oid = object["oid"].as_str().unwrap();
+                    serde_json::json!({
+                        "oid": oid,
+                        "size": object["size"],
+                        "actions": {
+                            "download": {
+                                "href": format!("{}/objects/{}", endpoint, oid),
+                                "header": { "X-Test": "1" },
+                            },
+                        },
+                    })
+                })
+                .collect();
+
+            Json(serde_json::json!({ "objects": objects }))
+        };
+
+        let router = axum::Router::new()
+            .route("/repo.git/info/lfs/objects/batch", post(batch))
+            .route(
+                "/repo.git/info/lfs/objects/{oid}",
+                get(
+                    move |UrlPath(_): UrlPath<String>, headers: axum::http::HeaderMap| async move {
+                        // Headers of the download action must be passed along
+                        match headers.get("X-Test") {
+                            Some(_) => Ok(content),
+                            None => Err(axum::http::StatusCode::FORBIDDEN),
+                        }
+                    },
+                ),
+            )
+            .with_state(Arc::new(endpoint.clone()));
+
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        endpoint
+    }
+
+    #[test]
+    fn parse_pointer() {
+        let oid = oid(CONTENT);
+        let data = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 19\n",
+            oid
+        );
+
+        let pointer = Pointer::parse(PathBuf::from("file.bin"), data.as_bytes()).unwrap();
+        assert_eq!(pointer.oid, oid);
+        assert_eq!(pointer.size, 19);
+    }
+
+    #[test]
+    fn parse_no_pointer() {
+        let parse = |data: String| Pointer::parse(PathBuf::from("file.bin"), data.as_bytes());
+        let oid = oid(CONTENT);
+
+        assert!(parse("just some file\n".to_owned()).is_none());
+        assert!(parse(format!("oid sha256:{}\nsize 19\n", oid)).is_none());
+        assert!(parse(format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\n",
+            oid
+        ))
+        .is_none());
+        assert!(parse(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:1234\nsize 19\n".to_owned()
+        )
+        .is_none());
+        assert!(parse(format!(
+            "version https://git-lfs.github.com/spec/v1\noid md5:{}\nsize 19\n",
+            oid
+        ))
+        .is_none());
+    }
+
+    #[test]
+    fn endpoint_https() {
+        assert_eq!(
+            endpoint("https://example.com/org/repo.git").unwrap(),
+            "https://example.com/org/repo.git/info/lfs"
+        );
+        assert_eq!(
+            endpoint("https://example.com/org/repo/").unwrap(),
+            "https://example.com/org/repo.git/info/lfs"
+        );
+    }
+
+    #[test]
+    fn endpoint_ssh() {
+        assert_eq!(
+            endpoint("ssh://git@example.com/org/repo.git").unwrap(),
+            "https://example.com/org/repo.git/info/lfs"
+        );
+        assert_eq!(
+            endpoint("ssh://git@example.com:2222/org/repo").unwrap(),
+            "https://example.com/org/repo.git/info/lfs"
+        );
+    }
+
+    #[test]
+    fn endpoint_scp_like() {
+        assert_eq!(
+            endpoint("git@example.com:org/repo.git").unwrap(),
+            "https://example.com/org/repo.git/info/lfs"
+        );
+        assert_eq!(
+            endpoint("example.com:/org/repo").unwrap(),
+            "https://example.com/org/repo.git/info/lfs"
+        );
+    }
+
+    #[test]
+    fn endpoint_unsupported() {
+        assert!(endpoint("file:///srv/repo.git").is_err());
+        assert!(endpoint("/srv/repo.git").is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_objects() {
+        let endpoint = server(CONTENT).await;
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store {
+            dir: dir.path().to_owned(),
+        };
+
+        let pointer = pointer(CONTENT);
+        fetch(&store, &endpoint, None, &[pointer.clone(), pointer.clone()])
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(store.object(&pointer.oid)).unwrap(), CONTENT);
+    }
+
+    #[tokio::test]
+    async fn fetch_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store {
+            dir: dir.path().to_owned(),
+        };
+
+        // Same size, but different content
+        let endpoint = server(b"LARGE FILE CONTENT\n").await;
+        let pointers = [pointer(CONTENT)];
+        assert!(fetch(&store, &endpoint, None, &pointers).await.is_err());
+        assert!(!store.object(&pointers[0].oid).exists());
+
+        // Same content prefix, but different size
+        let endpoint = server(b"large file content\n\n").await;
+        assert!(fetch(&store, &endpoint, None, &pointers).await.is_err());
+        assert!(!store.object(&pointers[0].oid).exists());
+    }
+}
@@ -1,18 +1,36 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use config::Config;
+use clap::{Parser, Subcommand};
+use config::{Config, Hook, HookFailure};
 use futures::future::FutureExt;
-use repo::Repo;
-use std::path::PathBuf;
-use std::process::Stdio;
+use history::HookResult;
+use metrics::{RepoLabels, METRICS};
+use queue::{Queue, Trigger};
+use registry::Registry;
+use repo::{HookStatus, Repo};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::time::{Instant, SystemTime};
+use tokio::sync::Semaphore;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
-use tracing::{debug, error, info_span, trace, Instrument, Level};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument, Level};
 
+mod api;
+mod check;
+mod client;
 mod config;
+mod history;
+mod hook;
+mod lfs;
+mod metrics;
+mod queue;
+mod registry;
+mod release;
 mod repo;
+mod sparse;
+mod timestamp;
+mod verify;
 mod webhook;
 
 #[derive(Parser, Debug)]
@@ -24,8 +42,35 @@ struct Args {
     #[arg(short = 'w', long = "webhook-listen", default_value = "localhost:8000")]
     webhook_listen: String,
 
+    #[arg(short = 'l', long = "control-listen", default_value = "localhost:8001")]
+    control_listen: String,
+
+    #[arg(short = 'j', long = "workers", default_value = "4")]
+    workers: NonZeroUsize,
+
     #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count, default_value = "0")]
     verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate the configuration and exit
+    Check,
+
+    /// Show the changes checked out for a repository
+    History { repo: String },
+
+    /// Check out the commit of a history entry and pin it until released
+    Rollback { repo: String, entry: u64 },
+
+    /// Release the pin of a rollback to follow the remote again
+    Release { repo: String },
+
+    /// Check out pending changes immediately, even outside of the deploy windows
+    Deploy { repo: String },
 }
 
 #[tokio::main]
@@ -41,56 +86,85 @@ async fn main() -> Result<()> {
         })
         .init();
 
+    match args.command {
+        None => {}
+        Some(Command::Check) => {
+            if !check::check(&args.config).await? {
+                std::process::exit(1);
+            }
+
+            return Ok(());
+        }
+        Some(Command::History { ref repo }) => {
+            return client::Client::new(&args.control_listen)
+                .history(repo)
+                .await;
+        }
+        Some(Command::Rollback { ref repo, entry }) => {
+            return client::Client::new(&args.control_listen)
+                .rollback(repo, entry)
+                .await;
+        }
+        Some(Command::Release { ref repo }) => {
+            return client::Client::new(&args.control_listen)
+                .release(repo)
+                .await;
+        }
+        Some(Command::Deploy { ref repo }) => {
+            return client::Client::new(&args.control_listen).deploy(repo).await;
+        }
+    }
+
     let config = Config::load(&args.config)
         .await
         .with_context(|| format!("Failed to load config from {}", args.config.display()))?;
 
-    let repos: Vec<Arc<Repo>> = config
-        .into_iter()
-        .map(|(name, config)| Arc::new(Repo::new(name, config)))
-        .collect();
-
-    // A single global worker queue to serialize all update checks
-    let (producer, mut consumer) = tokio::sync::mpsc::channel(repos.len() + 1);
+    // A single global queue feeding all update workers
+    let (queue, mut consumer) = Queue::new();
 
     let running = CancellationToken::new();
     let tasks = TaskTracker::new();
 
-    // Create periodic update tasks for all repos
-    for repo in repos.iter().cloned() {
-        let Some(interval) = &repo.config.interval else {
-            continue;
-        };
+    // Create all repos and their periodic update tasks
+    let registry = Arc::new(Registry::new(queue.clone(), running.clone(), tasks.clone()));
+    registry.apply(config);
 
-        let interval = interval.interval;
-
-        let producer = producer.clone();
-        let running = running.clone();
-
-        tasks.spawn(async move {
-            let mut interval = tokio::time::interval(interval);
-            loop {
-                tokio::select! {
-                    _ = interval.tick() => {
-                        producer.send(repo.clone()).await.expect("Receiver closed");
-                    }
+    // Reload config on changes
+    tasks.spawn({
+        let registry = registry.clone();
+        let path = args.config.clone();
 
-                    _ = running.cancelled() => {
-                        break;
-                    }
-                }
+        async move {
+            if let Err(err) = registry.watch(&path).await {
+                error!("Error while watching config: {:#}", err);
             }
-        });
-    }
+        }
+    });
 
     // Start web server
     tasks.spawn(webhook::serve(
         args.webhook_listen,
         running.clone(),
-        producer.clone(),
-        &repos,
+        queue.clone(),
+        registry.clone(),
     ));
 
+    // Start control server
+    tasks.spawn({
+        let task = api::serve_control(
+            args.control_listen,
+            running.clone(),
+            queue.clone(),
+            registry.clone(),
+        );
+
+        async move {
+            if let Err(err) = task.await {
+                error!("Error while serving control requests: {:#}", err);
+            }
+        }
+    });
+
     // Listen for shutdown signal
     tasks.spawn({
         let running = running.clone();
@@ -104,6 +178,9 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Limit the number of concurrently running updates
+    let workers = Arc::new(Semaphore::new(args.workers.get()));
+
     // Handle refresh tasks from queue
     loop {
         tokio::select! {
@@ -117,14 +194,31 @@ async fn main() -> Result<()> {
                     break;
                 };
 
-                let task = precess(repo.clone());
-                let task = task.map(|result| match result {
-                    Ok(_) => { trace!("Update successful"); }
-                    Err(err) => { error!("Error while updating: {:#}", err); }
-                });
-                let task = task.instrument(info_span!("Update repo", repo = repo.name));
+                let workers = workers.clone();
+                let running = running.clone();
+                let queue = queue.clone();
+
+                let task = async move {
+                    let Some(_permit) = running.run_until_cancelled(workers.acquire_owned()).await else {
+                        return;
+                    };
 
-                task.await;
+                    // The repo is never queued again before it has finished
+                    let trigger = queue.start(&repo);
+
+                    let task = precess(repo.clone(), trigger, running);
+                    let task = task.inspect(|result| match result {
+                        Ok(_) => { trace!("Update successful"); }
+                        Err(err) => { error!("Error while updating: {:#}", err); }
+                    });
+                    let task = task.instrument(info_span!("Update repo", repo = repo.name));
+
+                    repo.record(&task.await);
+
+                    queue.finish(&repo);
+                };
+
+                tasks.spawn(task);
             }
         }
     }
@@ -132,53 +226,269 @@ async fn main() -> Result<()> {
     tasks.close();
     tasks.wait().await;
 
-    return Ok(());
+    Ok(())
 }
 
-async fn precess(repo: Arc<Repo>) -> Result<()> {
-    let changed = repo
-        .update()
-        .await
-        .with_context(|| format!("Error while update {}", repo.name))?;
+async fn precess(repo: Arc<Repo>, trigger: Trigger, running: CancellationToken) -> Result<()> {
+    let labels = RepoLabels::new(&repo.name);
 
-    if !changed {
-        trace!("No changes");
+    // The whole update uses the same config, even if it is reloaded in the meantime
+    let config = repo.config();
+
+    let started = Instant::now();
+    let fetched = repo.fetch(&config).await;
+
+    METRICS.fetches.get_or_create(&labels).inc();
+    METRICS
+        .fetch_duration
+        .get_or_create(&labels)
+        .observe(started.elapsed().as_secs_f64());
+
+    let change = match fetched {
+        Ok(change) => {
+            let now = metrics::timestamp(SystemTime::now());
+            METRICS.last_success.get_or_create(&labels).set(now);
+
+            change
+        }
+        Err(err) => {
+            METRICS.fetch_failures.get_or_create(&labels).inc();
+            return Err(err.context(format!("Error while update {}", repo.name)));
+        }
+    };
+
+    // Outside of the deploy windows changes are fetched only
+    if !trigger.forced() && !config.in_deploy_window(chrono::Utc::now()) {
+        if let Some(ref change) = change {
+            info!(
+                "Deferring update to {} until the next deploy window",
+                change.new
+            );
+            repo.defer(change);
+        }
         return Ok(());
     }
 
-    let Some(ref script) = repo.config.on_change else {
+    // Changes outside of the configured paths are checked out without running any script
+    let change = match change {
+        Some(change) if !config.paths.is_empty() && change.files.is_empty() => {
+            debug!("No changes in configured paths");
+            repo.checkout(&config, &change)
+                .await
+                .with_context(|| format!("Error while update {}", repo.name))?;
+            repo.record_history(&config, &change, trigger.as_str(), None, started.elapsed())
+                .await;
+            None
+        }
+        change => change,
+    };
+
+    if let Some(ref change) = change {
+        if let Some(ref script) = config.pre_change {
+            // The script validates an exported copy as the working tree is still untouched
+            let tree = tempfile::tempdir().context("Failed to create tree directory")?;
+            repo.export(&config, change.new, tree.path())?;
+
+            let env = hook::Env::new(&repo.name, &config, trigger, change)?.tree(tree.path());
+            run_hook(&repo, script, tree.path(), &config, &env, &running)
+                .await
+                .with_context(|| format!("Update to {} vetoed", change.new))?;
+        }
+
+        repo.checkout(&config, change)
+            .await
+            .with_context(|| format!("Error while update {}", repo.name))?;
+
+        let now = metrics::timestamp(SystemTime::now());
+        METRICS.last_change.get_or_create(&labels).set(now);
+    }
+
+    let checked_out = change.clone();
+
+    // A change whose script has failed before is handled again together with the new one
+    let change = match (repo.take_failed_change(), change) {
+        (None, None) => {
+            trace!("No changes");
+            return Ok(());
+        }
+        (Some(failed), None) => {
+            debug!("Retrying failed script");
+            failed
+        }
+        (None, Some(change)) => change,
+        (Some(failed), Some(change)) => failed.merge(change),
+    };
+
+    let hooks: Vec<_> = config
+        .on_change
+        .iter()
+        .filter(|hook| hook.matches(&change.files))
+        .collect();
+    if hooks.is_empty() {
         trace!("No script to execute");
+        if let Some(ref checked_out) = checked_out {
+            repo.record_history(
+                &config,
+                checked_out,
+                trigger.as_str(),
+                None,
+                started.elapsed(),
+            )
+            .await;
+        }
         return Ok(());
-    };
+    }
+
+    let env = hook::Env::new(&repo.name, &config, trigger, &change)?;
+
+    let result = run_hooks(
+        &repo,
+        &hooks,
+        &repo.worktree(&config, change.new),
+        &config,
+        &env,
+        &running,
+    )
+    .await;
+
+    if let Some(ref checked_out) = checked_out {
+        let hook = match result {
+            Ok(()) => HookResult::Success,
+            Err(_) => HookResult::Failure,
+        };
+        repo.record_history(
+            &config,
+            checked_out,
+            trigger.as_str(),
+            Some(hook),
+            started.elapsed(),
+        )
+        .await;
+    }
 
-    let mut child = tokio::process::Command::new("sh")
-        .arg("-c")
-        .arg(script)
-        .current_dir(&repo.config.path)
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("Failed to spawn script")?;
+    let Err(ref err) = result else {
+        return Ok(());
+    };
 
-    let mut stdout = BufReader::new(child.stdout.take().expect("Failed to take stdout")).lines();
-    let mut stderr = BufReader::new(child.stderr.take().expect("Failed to take stderr")).lines();
+    // The change is kept as it is if the script was stopped by a shutdown instead of failing
+    if err
+        .downcast_ref::<hook::Terminated>()
+        .is_some_and(|terminated| terminated.reason == hook::Reason::Interrupted)
+    {
+        return result;
+    }
 
-    loop {
-        tokio::select! {
-            Ok(Some(line)) = stdout.next_line() => {
-                trace!("> {}", line);
+    if config.rollback_on_failure {
+        let started = Instant::now();
+        match repo.rollback(&config, &change).await? {
+            Some(rollback) => {
+                let env = hook::Env::new(&repo.name, &config, trigger, &rollback)?.rollback();
+                let hook = match run_hooks(
+                    &repo,
+                    &hooks,
+                    &repo.worktree(&config, rollback.new),
+                    &config,
+                    &env,
+                    &running,
+                )
+                .await
+                {
+                    Ok(()) => HookResult::Success,
+                    Err(err) => {
+                        error!("Script failed after rollback: {:#}", err);
+                        HookResult::Failure
+                    }
+                };
+                repo.record_history(
+                    &config,
+                    &rollback,
+                    "rollback",
+                    Some(hook),
+                    started.elapsed(),
+                )
+                .await;
             }
-
-            Ok(Some(line)) = stderr.next_line() => {
-                trace!("! {}", line);
+            None => {
+                warn!("No previous commit to roll back to");
             }
+        }
 
-            else => break,
+        return result;
+    }
+
+    match config.on_change_failure {
+        HookFailure::Ignore => {}
+        HookFailure::Retry => {
+            debug!("Script will be retried on next update");
+            repo.record_failed_change(change);
         }
     }
 
-    child.wait().await.context("Failed to wait for script")?;
+    result
+}
+
+/// Runs the scripts of the hooks one after another until one fails.
+async fn run_hooks(
+    repo: &Repo,
+    hooks: &[&Hook],
+    path: &Path,
+    config: &Config,
+    env: &hook::Env,
+    running: &CancellationToken,
+) -> Result<()> {
+    for hook in hooks {
+        run_hook(repo, &hook.script, path, config, env, running).await?;
+    }
 
     Ok(())
 }
+
+/// Runs the script and records its outcome. A non-zero exit status is reported as error.
+async fn run_hook(
+    repo: &Repo,
+    script: &str,
+    path: &Path,
+    config: &Config,
+    env: &hook::Env,
+    running: &CancellationToken,
+) -> Result<()> {
+    let labels = RepoLabels::new(&repo.name);
+
+    let started = Instant::now();
+    let status = hook::run(script, path, env, config.on_change_timeout, running).await;
+
+    METRICS.hook_runs.get_or_create(&labels).inc();
+    METRICS
+        .hook_duration
+        .get_or_create(&labels)
+        .observe(started.elapsed().as_secs_f64());
+
+    let result = match status {
+        Ok(status) => {
+            repo.record_hook(HookStatus::new(status));
+
+            if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("Script failed with {}", status))
+            }
+        }
+        Err(err) => {
+            // Scripts killed after a timeout or on shutdown are reported with their signal
+            if let Some(status) = err
+                .downcast_ref::<hook::Terminated>()
+                .and_then(|terminated| terminated.status)
+            {
+                repo.record_hook(HookStatus::new(status));
+            }
+
+            Err(err)
+        }
+    };
+
+    if result.is_err() {
+        METRICS.hook_failures.get_or_create(&labels).inc();
+    }
+
+    result
+}
@@ -0,0 +1,207 @@
+use crate::webhook::Provider;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::{Registry, Unit};
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RepoLabels {
+    pub repo: String,
+}
+
+impl RepoLabels {
+    pub fn new(repo: &str) -> Self {
+        Self {
+            repo: repo.to_owned(),
+        }
+    }
+}
+
+impl EncodeLabelValue for Provider {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        encoder.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Outcome {
+    Triggered,
+    Ignored,
+    Rejected,
+}
+
+impl EncodeLabelValue for Outcome {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        encoder.write_str(match self {
+            Outcome::Triggered => "triggered",
+            Outcome::Ignored => "ignored",
+            Outcome::Rejected => "rejected",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct WebhookLabels {
+    pub provider: Provider,
+    pub outcome: Outcome,
+}
+
+type DurationFamily = Family<RepoLabels, Histogram, fn() -> Histogram>;
+
+fn duration_histogram() -> Histogram {
+    // From 10ms up to ~5min
+    Histogram::new(exponential_buckets(0.01, 2.0, 16))
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+
+    pub fetches: Family<RepoLabels, Counter>,
+    pub fetch_failures: Family<RepoLabels, Counter>,
+    pub fetch_duration: DurationFamily,
+
+    pub hook_runs: Family<RepoLabels, Counter>,
+    pub hook_failures: Family<RepoLabels, Counter>,
+    pub hook_duration: DurationFamily,
+
+    pub webhook_requests: Family<WebhookLabels, Counter>,
+
+    pub queue_depth: Gauge,
+
+    pub last_success: Family<RepoLabels, Gauge<f64, AtomicU64>>,
+    pub last_change: Family<RepoLabels, Gauge<f64, AtomicU64>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut registry = Registry::with_prefix("pullomatic");
+
+        let fetches = Family::default();
+        registry.register("fetches", "Number of update checks", fetches.clone());
+
+        let fetch_failures = Family::default();
+        registry.register(
+            "fetch_failures",
+            "Number of failed update checks",
+            fetch_failures.clone(),
+        );
+
+        let fetch_duration = DurationFamily::new_with_constructor(duration_histogram);
+        registry.register_with_unit(
+            "fetch_duration",
+            "Duration of update checks",
+            Unit::Seconds,
+            fetch_duration.clone(),
+        );
+
+        let hook_runs = Family::default();
+        registry.register("hook_runs", "Number of hook script runs", hook_runs.clone());
+
+        let hook_failures = Family::default();
+        registry.register(
+            "hook_failures",
+            "Number of failed hook script runs",
+            hook_failures.clone(),
+        );
+
+        let hook_duration = DurationFamily::new_with_constructor(duration_histogram);
+        registry.register_with_unit(
+            "hook_duration",
+            "Duration of hook script runs",
+            Unit::Seconds,
+            hook_duration.clone(),
+        );
+
+        let webhook_requests = Family::default();
+        registry.register(
+            "webhook_requests",
+            "Number of webhook requests by provider and outcome",
+            webhook_requests.clone(),
+        );
+
+        let queue_depth = Gauge::default();
+        registry.register(
+            "queue_depth",
+            "Number of repos waiting for an update",
+            queue_depth.clone(),
+        );
+
+        let last_success = Family::default();
+        registry.register(
+            "last_success_timestamp",
+            "Time of the last successful update check as UNIX timestamp",
+            last_success.clone(),
+        );
+
+        let last_change = Family::default();
+        registry.register(
+            "last_change_timestamp",
+            "Time of the last change as UNIX timestamp",
+            last_change.clone(),
+        );
+
+        Self {
+            registry,
+            fetches,
+            fetch_failures,
+            fetch_duration,
+            hook_runs,
+            hook_failures,
+            hook_duration,
+            webhook_requests,
+            queue_depth,
+            last_success,
+            last_change,
+        }
+    }
+
+    /// Drops all series of a removed repo.
+    pub fn forget(&self, repo: &str) {
+        let labels = RepoLabels::new(repo);
+
+        self.fetches.remove(&labels);
+        self.fetch_failures.remove(&labels);
+        self.fetch_duration.remove(&labels);
+        self.hook_runs.remove(&labels);
+        self.hook_failures.remove(&labels);
+        self.hook_duration.remove(&labels);
+        self.last_success.remove(&labels);
+        self.last_change.remove(&labels);
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn timestamp(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+pub async fn handle() -> impl IntoResponse {
+    let mut buffer = String::new();
+    if prometheus_client::encoding::text::encode(&mut buffer, &METRICS.registry).is_err() {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to encode metrics",
+        ));
+    }
+
+    Ok((
+        [(
+            CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        buffer,
+    ))
+}
@@ -0,0 +1,87 @@
+use crate::metrics::METRICS;
+use crate::repo::Repo;
+use crate::webhook::Provider;
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// The reason an update was requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Interval,
+    Webhook(Provider),
+    Manual,
+
+    /// A deploy window opened while a change is pending
+    Window,
+
+    /// Manual update checking out changes outside of the deploy windows
+    Deploy,
+
+    /// Manual rollback to an earlier commit
+    Rollback,
+}
+
+impl Trigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Interval => "interval",
+            Trigger::Webhook(_) => "webhook",
+            Trigger::Manual => "manual",
+            Trigger::Window => "window",
+            Trigger::Deploy => "deploy",
+            Trigger::Rollback => "rollback",
+        }
+    }
+
+    /// Whether the update may check out changes outside of the deploy windows.
+    ///
+    /// A window trigger is created while the window is open, so the update checks out the change
+    /// even if the window has closed while fetching or waiting for a worker.
+    pub fn forced(&self) -> bool {
+        matches!(self, Trigger::Window | Trigger::Deploy | Trigger::Rollback)
+    }
+}
+
+/// Queue of repos waiting for an update.
+///
+/// Each repo is contained at most once, so triggering an update never blocks.
+#[derive(Clone, Debug)]
+pub struct Queue {
+    producer: UnboundedSender<Arc<Repo>>,
+}
+
+impl Queue {
+    pub fn new() -> (Self, UnboundedReceiver<Arc<Repo>>) {
+        let (producer, consumer) = unbounded_channel();
+        (Self { producer }, consumer)
+    }
+
+    /// Requests an update of the repo.
+    ///
+    /// Triggers for a repo which is already queued are dropped. If the repo is currently updating,
+    /// a single follow-up update is queued as soon as the running one has finished.
+    pub fn trigger(&self, repo: &Arc<Repo>, trigger: Trigger) {
+        if repo.enqueue(trigger) {
+            self.send(repo);
+        }
+    }
+
+    /// Marks the repo as taken from the queue.
+    pub fn start(&self, repo: &Arc<Repo>) -> Trigger {
+        METRICS.queue_depth.dec();
+        repo.start()
+    }
+
+    /// Marks the update of the repo as finished and requeues it if triggered in the meantime.
+    pub fn finish(&self, repo: &Arc<Repo>) {
+        if repo.finish() {
+            self.send(repo);
+        }
+    }
+
+    fn send(&self, repo: &Arc<Repo>) {
+        // Receiver is only gone while shutting down
+        let _ = self.producer.send(repo.clone());
+        METRICS.queue_depth.inc();
+    }
+}
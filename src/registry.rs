@@ -0,0 +1,262 @@
+use crate::config::Config;
+use crate::metrics::METRICS;
+use crate::queue::{Queue, Trigger};
+use crate::repo::Repo;
+use anyhow::{Context, Result};
+use notify::{RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, warn};
+
+/// Time to wait for further file system events before reloading the config
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Time between checks whether a deploy window has opened
+const WINDOW_CHECK: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct Entry {
+    repo: Arc<Repo>,
+
+    // Cancels the periodic update task of this repo
+    interval: CancellationToken,
+}
+
+/// The set of currently configured repositories.
+#[derive(Debug)]
+pub struct Registry {
+    entries: RwLock<HashMap<String, Entry>>,
+
+    queue: Queue,
+
+    running: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Registry {
+    pub fn new(queue: Queue, running: CancellationToken, tasks: TaskTracker) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            queue,
+            running,
+            tasks,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Repo>> {
+        let entries = self.entries.read().expect("registry lock poisoned");
+        entries.get(name).map(|entry| entry.repo.clone())
+    }
+
+    /// Returns all repos ordered by name.
+    pub fn repos(&self) -> Vec<Arc<Repo>> {
+        let entries = self.entries.read().expect("registry lock poisoned");
+
+        let mut repos: Vec<_> = entries.values().map(|entry| entry.repo.clone()).collect();
+        repos.sort_by(|a, b| a.name.cmp(&b.name));
+        repos
+    }
+
+    /// Applies the given configs by adding new repos, removing vanished ones and reconfiguring
+    /// the changed ones. Repos with an unchanged config are kept untouched.
+    pub fn apply(&self, mut configs: HashMap<String, Config>) {
+        let mut entries = self.entries.write().expect("registry lock poisoned");
+
+        entries.retain(|name, entry| {
+            if configs.contains_key(name) {
+                return true;
+            }
+
+            info!("Removing repository {}", name);
+            entry.interval.cancel();
+            METRICS.forget(name);
+            false
+        });
+
+        for (name, config) in configs.drain() {
+            if let Some(entry) = entries.get_mut(&name) {
+                if *entry.repo.config() == config {
+                    continue;
+                }
+
+                info!("Reconfiguring repository {}", name);
+                entry.interval.cancel();
+                entry.repo.reconfigure(config);
+                entry.interval = self.schedule(entry.repo.clone());
+            } else {
+                info!("Adding repository {}", name);
+                let repo = Arc::new(Repo::new(name.clone(), config));
+                let interval = self.schedule(repo.clone());
+                entries.insert(name, Entry { repo, interval });
+            }
+        }
+    }
+
+    /// Loads the config from the given path and applies it.
+    pub async fn reload(&self, path: &Path) -> Result<()> {
+        let configs = Config::load(path)
+            .await
+            .with_context(|| format!("Failed to load config from {}", path.display()))?;
+
+        self.apply(configs);
+
+        Ok(())
+    }
+
+    /// Watches the config directory and reloads the config on changes or on SIGHUP.
+    pub async fn watch(&self, path: &Path) -> Result<()> {
+        // Installed first so a SIGHUP never terminates the daemon, even without a watcher
+        let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+
+        let (notify_tx, mut notify_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = notify_tx.send(event);
+        })
+        .context("Failed to create config watcher")
+        .and_then(|mut watcher| {
+            watcher
+                .watch(path, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch config directory: {}", path.display()))?;
+            Ok(watcher)
+        });
+
+        // Without a watcher the config is still reloaded on SIGHUP
+        let _watcher = match watcher {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                error!("Reloading config on SIGHUP only: {:#}", err);
+                None
+            }
+        };
+
+        loop {
+            tokio::select! {
+                _ = self.running.cancelled() => {
+                    break;
+                }
+
+                _ = hangup.recv() => {
+                    debug!("Received SIGHUP");
+                }
+
+                Some(event) = notify_rx.recv() => {
+                    match event {
+                        Ok(event) if event.kind.is_access() => continue,
+                        Ok(event) => debug!("Config changed: {:?}", event.paths),
+                        Err(err) => error!("Error while watching config: {}", err),
+                    }
+
+                    // Editors and deployment tools tend to produce multiple events in a row
+                    while let Ok(Some(_)) = tokio::time::timeout(SETTLE_DELAY, notify_rx.recv()).await {}
+                }
+            }
+
+            info!("Reloading config");
+            if let Err(err) = self.reload(path).await {
+                error!("Keeping previous config: {:#}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn schedule(&self, repo: Arc<Repo>) -> CancellationToken {
+        let cancelled = self.running.child_token();
+
+        let config = repo.config();
+
+        if !config.deploy_windows.is_empty() {
+            self.tasks.spawn(Self::watch_windows(
+                repo.clone(),
+                self.queue.clone(),
+                cancelled.clone(),
+            ));
+        }
+
+        if config.interval.is_none() && config.schedule.is_empty() {
+            return cancelled;
+        }
+
+        let queue = self.queue.clone();
+
+        self.tasks.spawn({
+            let cancelled = cancelled.clone();
+
+            async move {
+                let mut interval = config
+                    .interval
+                    .as_ref()
+                    .map(|interval| tokio::time::interval(interval.interval));
+                let mut scheduled = chrono::Local::now();
+
+                loop {
+                    let tick = async {
+                        match interval {
+                            Some(ref mut interval) => {
+                                interval.tick().await;
+                            }
+                            None => {
+                                let Some(next) = config.next_scheduled(&scheduled) else {
+                                    warn!("No scheduled check left");
+                                    return std::future::pending().await;
+                                };
+
+                                let delay = next - chrono::Local::now();
+                                tokio::time::sleep(delay.to_std().unwrap_or_default()).await;
+                                scheduled = next;
+                            }
+                        }
+
+                        // Spreads the checks of multiple hosts polling the same remote
+                        if let Some(jitter) = config.jitter {
+                            tokio::time::sleep(jitter.mul_f64(rand::random())).await;
+                        }
+                    };
+
+                    tokio::select! {
+                        _ = tick => {
+                            queue.trigger(&repo, Trigger::Interval);
+                        }
+
+                        _ = cancelled.cancelled() => {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        cancelled
+    }
+
+    /// Triggers an update of a pending change as soon as a deploy window opens.
+    async fn watch_windows(repo: Arc<Repo>, queue: Queue, cancelled: CancellationToken) {
+        let config = repo.config();
+
+        let mut check = tokio::time::interval(WINDOW_CHECK);
+        let mut open = config.in_deploy_window(chrono::Utc::now());
+
+        loop {
+            tokio::select! {
+                _ = check.tick() => {
+                    let was_open = open;
+                    open = config.in_deploy_window(chrono::Utc::now());
+                    if open && !was_open && repo.pending() {
+                        debug!("Deploy window opened");
+                        queue.trigger(&repo, Trigger::Window);
+                    }
+                }
+
+                _ = cancelled.cancelled() => {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Deploys commits into separate release directories switched by a `current` symlink.
+
+use crate::{lfs, sparse};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+const RELEASES_DIR: &str = "releases";
+const CURRENT_LINK: &str = "current";
+
+/// Directory containing the tree of the commit.
+pub fn path(path: &Path, commit: git2::Oid) -> PathBuf {
+    path.join(RELEASES_DIR).join(commit.to_string())
+}
+
+/// Checks out the commit into its own release directory and switches the `current` symlink to it.
+///
+/// Releases exceeding `keep` are removed, oldest first.
+pub fn deploy(
+    repository: &git2::Repository,
+    path: &Path,
+    commit: git2::Oid,
+    keep: usize,
+    filter: Option<&sparse::Filter>,
+    lfs: Option<&(lfs::Store, Vec<lfs::Pointer>)>,
+) -> Result<()> {
+    let release = self::path(path, commit);
+
+    if release.exists() {
+        debug!("Reusing release {}", release.display());
+
+        // Releases are ordered by their modification time
+        std::fs::File::open(&release)
+            .and_then(|dir| dir.set_modified(SystemTime::now()))
+            .with_context(|| format!("Failed to touch release: {}", release.display()))?;
+    } else {
+        // Check out into a hidden directory first to never expose an incomplete release
+        let staging = path.join(RELEASES_DIR).join(format!(".{}", commit));
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)?;
+
+        crate::repo::export_tree(repository, commit, &staging, filter)
+            .with_context(|| format!("Failed to check out release: {}", commit))?;
+
+        if let Some((store, pointers)) = lfs {
+            store.smudge(&staging, pointers)?;
+        }
+
+        std::fs::rename(&staging, &release)
+            .with_context(|| format!("Failed to create release: {}", release.display()))?;
+    }
+
+    // Renaming a new symlink over the existing one replaces it atomically
+    let link = path.join(format!(".{}", CURRENT_LINK));
+    if link.symlink_metadata().is_ok() {
+        std::fs::remove_file(&link)?;
+    }
+    std::os::unix::fs::symlink(Path::new(RELEASES_DIR).join(commit.to_string()), &link)
+        .context("Failed to create current symlink")?;
+    std::fs::rename(&link, path.join(CURRENT_LINK)).context("Failed to switch current symlink")?;
+
+    repository.set_head_detached(commit)?;
+
+    info!("Switched to release {}", commit);
+
+    prune(path, commit, keep)
+}
+
+/// Removes the oldest releases keeping the current one.
+fn prune(path: &Path, current: git2::Oid, keep: usize) -> Result<()> {
+    let current = current.to_string();
+
+    let mut releases = Vec::new();
+    for entry in std::fs::read_dir(path.join(RELEASES_DIR))? {
+        let entry = entry?;
+
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') || name == current.as_str() {
+            continue;
+        }
+
+        releases.push((entry.metadata()?.modified()?, entry.path()));
+    }
+
+    // Newest first
+    releases.sort_by(|a, b| b.cmp(a));
+
+    for (_, release) in releases.into_iter().skip(keep.saturating_sub(1)) {
+        debug!("Removing release {}", release.display());
+        std::fs::remove_dir_all(&release)
+            .with_context(|| format!("Failed to remove release: {}", release.display()))?;
+    }
+
+    Ok(())
+}
@@ -1,94 +1,1188 @@
-use crate::config::{Config, Credentials};
+use crate::config::{Config, Credentials, LocalChanges, Submodules, TagSelector};
+use crate::history::{self, History};
+use crate::lfs;
+use crate::queue::Trigger;
+use crate::release;
+use crate::sparse;
+use crate::verify;
 use anyhow::{Context, Result};
-use std::time::Instant;
-use tokio::sync::Mutex;
-use tracing::{debug, info, trace};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, trace, warn};
 
-#[derive(Debug)]
-struct RepoState {
-    last_checked: Option<Instant>,
-    last_changed: Option<Instant>,
+#[derive(Debug, Clone, Serialize)]
+pub struct HookStatus {
+    #[serde(with = "crate::timestamp")]
+    pub finished: SystemTime,
+
+    pub success: bool,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl HookStatus {
+    pub fn new(status: ExitStatus) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+
+        Self {
+            finished: SystemTime::now(),
+            success: status.success(),
+            code: status.code(),
+            signal: status.signal(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepoState {
+    pub commit: Option<String>,
+    pub tag: Option<String>,
+
+    #[serde(with = "crate::timestamp::option")]
+    pub last_checked: Option<SystemTime>,
+    #[serde(with = "crate::timestamp::option")]
+    pub last_changed: Option<SystemTime>,
+
+    pub last_error: Option<String>,
+    pub failures: u32,
+
+    /// Whether the last update failed
+    pub failing: bool,
+
+    /// The error of the last update followed by its causes
+    pub error_chain: Vec<String>,
+
+    pub last_hook: Option<HookStatus>,
+
+    pub drift: Option<Drift>,
+
+    /// Commit pinned by a rollback until released
+    pub pin: Option<String>,
+
+    /// Files changed by hand in the working tree
+    pub dirty: Vec<PathBuf>,
+
+    /// Commit fetched but deferred until the next deploy window
+    pub pending: Option<String>,
+
+    // Commit rolled back after a failed script, skipped until the remote moves on
+    pub rejected_commit: Option<String>,
+
+    // Change whose script failed and must be retried
+    #[serde(skip)]
+    pub failed_change: Option<Change>,
+}
+
+/// Difference between the pinned commit and the remote head.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Drift {
+    pub remote_commit: String,
+
+    /// Commits on the remote missing in the pinned commit
+    pub behind: usize,
+
+    /// Commits in the pinned commit missing on the remote
+    pub ahead: usize,
+}
+
+impl Drift {
+    fn new(
+        repository: &git2::Repository,
+        pinned: &git2::Object,
+        remote: &git2::Object,
+    ) -> Result<Self> {
+        let (ahead, behind) = repository
+            .graph_ahead_behind(pinned.id(), remote.id())
+            .context("Failed to compare pin against remote")?;
+
+        Ok(Self {
+            remote_commit: remote.id().to_string(),
+            behind,
+            ahead,
+        })
+    }
+}
+
+/// Position of a repo in the update queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    Idle,
+    Queued(Trigger),
+    Running,
+
+    // Running with another update requested afterwards
+    Pending(Trigger),
+}
+
+/// A change checked out by an update.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub old: Option<git2::Oid>,
+    pub new: git2::Oid,
+
+    /// The remote tag pointing to the new commit if following tags
+    pub tag: Option<String>,
+
+    /// Files added, modified or deleted between the old and the new commit
+    pub files: Vec<PathBuf>,
+}
+
+impl Change {
+    /// Combines this change with a following one.
+    pub fn merge(self, next: Change) -> Change {
+        let files: BTreeSet<_> = self.files.into_iter().chain(next.files).collect();
+
+        Change {
+            old: self.old,
+            new: next.new,
+            tag: next.tag,
+            files: files.into_iter().collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Repo {
     pub name: String,
-    pub config: Config,
 
-    state: Mutex<RepoState>,
+    config: RwLock<Arc<Config>>,
+
+    // Never held across await points, so the status is available while updating
+    state: std::sync::Mutex<RepoState>,
+
+    schedule: std::sync::Mutex<Schedule>,
+
+    backoff: std::sync::Mutex<Backoff>,
+}
+
+/// Periodic triggers skipped after consecutive failures to fetch or check out.
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32,
+    skips: u32,
+
+    // Whether fetching or checking out has failed during the running update
+    failed: bool,
 }
 
 const TARGET_REF: &str = "refs/pullomatic";
 
+/// Fetch depth which converts a shallow repository into a complete one
+const UNSHALLOW: i32 = i32::MAX;
+
 impl Repo {
     pub fn new(name: String, config: Config) -> Self {
         Self {
             name,
-            config,
+            config: RwLock::new(Arc::new(config)),
+
+            state: std::sync::Mutex::new(RepoState::default()),
+
+            schedule: std::sync::Mutex::new(Schedule::Idle),
+
+            backoff: std::sync::Mutex::new(Backoff::default()),
+        }
+    }
+
+    /// Returns a snapshot of the current configuration.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().expect("config lock poisoned").clone()
+    }
+
+    /// Replaces the configuration used by all following updates.
+    pub fn reconfigure(&self, config: Config) {
+        *self.config.write().expect("config lock poisoned") = Arc::new(config);
+    }
+
+    pub fn schedule(&self) -> Schedule {
+        *self.schedule.lock().expect("schedule lock poisoned")
+    }
+
+    /// Requests an update. Returns `true` if the repo must be put into the queue.
+    ///
+    /// If an update has already been requested, an explicit trigger replaces a periodic one, but
+    /// never a forced one.
+    /// Periodic triggers are dropped while backing off after failures.
+    pub fn enqueue(&self, trigger: Trigger) -> bool {
+        if trigger == Trigger::Interval {
+            let mut backoff = self.backoff.lock().expect("backoff lock poisoned");
+            if backoff.skips > 0 {
+                trace!("Backing off");
+                backoff.skips -= 1;
+                return false;
+            }
+        }
+
+        let mut schedule = self.schedule.lock().expect("schedule lock poisoned");
+        match *schedule {
+            Schedule::Idle => {
+                *schedule = Schedule::Queued(trigger);
+                true
+            }
+            Schedule::Running => {
+                *schedule = Schedule::Pending(trigger);
+                false
+            }
+            Schedule::Queued(ref mut pending) | Schedule::Pending(ref mut pending) => {
+                if trigger != Trigger::Interval && (trigger.forced() || !pending.forced()) {
+                    *pending = trigger;
+                }
+                false
+            }
+        }
+    }
+
+    /// Marks the repo as taken from the queue and returns what triggered the update.
+    pub fn start(&self) -> Trigger {
+        let mut schedule = self.schedule.lock().expect("schedule lock poisoned");
+        let Schedule::Queued(trigger) = *schedule else {
+            panic!("repo not queued");
+        };
 
-            state: Mutex::new(RepoState {
-                last_checked: None,
-                last_changed: None,
-            }),
+        *schedule = Schedule::Running;
+        trigger
+    }
+
+    /// Marks the update as finished. Returns `true` if the repo must be put into the queue again.
+    pub fn finish(&self) -> bool {
+        let mut schedule = self.schedule.lock().expect("schedule lock poisoned");
+        match *schedule {
+            Schedule::Pending(trigger) => {
+                *schedule = Schedule::Queued(trigger);
+                true
+            }
+            _ => {
+                *schedule = Schedule::Idle;
+                false
+            }
         }
     }
 
-    pub async fn update(&self) -> Result<bool> {
-        let mut state = self.state.lock().await;
+    /// Fetches the remote and returns the change if the target commit differs from the local HEAD.
+    ///
+    /// The working tree is left untouched until the change is checked out.
+    pub async fn fetch(&self, config: &Config) -> Result<Option<Change>> {
+        let result = self.fetch_remote(config).await;
+        self.backoff_on(&result);
+        result
+    }
+
+    async fn fetch_remote(&self, config: &Config) -> Result<Option<Change>> {
+        self.lock_state().last_checked = Some(SystemTime::now());
 
-        let now = Some(Instant::now());
-        state.last_checked = now;
+        let path = config.repository_path();
+        let path = path.as_path();
 
-        let path = self.config.path.as_path();
+        // A pin set by a rollback takes precedence over the configured one
+        let history = History::new(config);
+        let rollback_pin = history.pin().await?;
+        let rejected = history.rejected().await?;
+        {
+            let mut state = self.lock_state();
+            state.pin = rollback_pin.clone();
+            state.rejected_commit = rejected.clone();
+        }
+        let pin = rollback_pin.or_else(|| config.pin.clone());
+
+        // Secrets must be loaded upfront as git2 types can not be held across await points
+        let auth = Auth::load(config.credentials.as_ref()).await?;
+        let keys = match config.verify {
+            Some(ref verify) => Some(verify::Keys::load(verify).await?),
+            None => None,
+        };
 
-        let repository: git2::Repository;
-        if path.exists() {
+        let repository = if path.exists() {
             debug!("Using existing repository");
-            repository = tokio::task::block_in_place(|| git2::Repository::open(path))?;
+            tokio::task::block_in_place(|| git2::Repository::open(path))?
         } else {
             debug!("Initialized new repository");
             tokio::fs::create_dir_all(path).await?;
-            repository = tokio::task::block_in_place(|| git2::Repository::init(path))?;
+            tokio::task::block_in_place(|| match config.releases {
+                // Releases have their own working trees
+                Some(_) => git2::Repository::init_bare(path),
+                None => git2::Repository::init(path),
+            })?
+        };
+
+        let mut remote = repository.remote_anonymous(&config.remote_url)?;
+
+        let (remote_ref, tag) = match (&config.remote_tag, &config.remote_ref) {
+            (Some(selector), _) => {
+                let tag = tokio::task::block_in_place(|| latest_tag(&mut remote, &auth, selector))?;
+                debug!("Following tag {}", tag);
+                (format!("refs/tags/{}", tag), Some(tag))
+            }
+            (None, Some(remote_ref)) => (remote_ref.clone(), None),
+            (None, None) => (
+                format!(
+                    "refs/heads/{}",
+                    config.remote_branch.as_deref().expect("validated config")
+                ),
+                None,
+            ),
+        };
+
+        let remote_cb = auth.callbacks();
+
+        debug!("Fetching data from remote");
+        tokio::task::block_in_place(|| {
+            // Fetch the remote ref into our target ref
+            remote
+                .fetch(
+                    &[&format!("+{}:{}", remote_ref, TARGET_REF)],
+                    Some(
+                        git2::FetchOptions::new()
+                            .prune(git2::FetchPrune::On)
+                            .download_tags(git2::AutotagOption::None)
+                            .depth(fetch_depth(&repository, config.depth))
+                            .remote_callbacks(remote_cb),
+                    ),
+                    None,
+                )
+                .with_context(|| format!("Failed to fetch data from remote: {}", remote_ref))
+        })?;
+        debug!("Fetched data from remote");
+
+        let latest_obj = repository.revparse_single("HEAD").ok();
+        self.lock_state().commit = latest_obj.as_ref().map(|obj| obj.id().to_string());
+
+        // Annotated tags must be peeled to compare them against HEAD
+        let remote_obj = repository
+            .revparse_single(TARGET_REF)
+            .expect("target ref fetched")
+            .peel(git2::ObjectType::Commit)?;
+
+        let (target_obj, tag) = match pin {
+            Some(ref pin) => {
+                let pinned_obj = tokio::task::block_in_place(|| {
+                    pinned_commit(&repository, &mut remote, &auth, pin, config.depth)
+                })?;
+
+                // Reported once per remote change to keep periodic checks quiet
+                let drift = Drift::new(&repository, &pinned_obj, &remote_obj)?;
+                let previous = self.lock_state().drift.replace(drift.clone());
+                if previous.as_ref() != Some(&drift) {
+                    if drift.ahead > 0 {
+                        warn!(
+                            "Pinned to {} diverged from the remote, remote is {} commit(s) ahead and {} behind",
+                            pinned_obj.id(),
+                            drift.behind,
+                            drift.ahead
+                        );
+                    } else if drift.behind > 0 {
+                        info!(
+                            "Pinned to {}, remote is {} commit(s) ahead",
+                            pinned_obj.id(),
+                            drift.behind
+                        );
+                    }
+                }
+
+                (pinned_obj, None)
+            }
+            None => {
+                self.lock_state().drift = None;
+                (remote_obj, tag)
+            }
+        };
+
+        if let Some(ref rejected) = rejected {
+            if *rejected == target_obj.id().to_string() {
+                debug!("Skipping rejected commit {}", rejected);
+                self.lock_state().pending = None;
+                return Ok(None);
+            }
+
+            debug!("Remote moved on from rejected commit {}", rejected);
+            tokio::task::block_in_place(|| history.clear_rejected())?;
+            self.lock_state().rejected_commit = None;
         }
 
-        let mut remote = repository.remote_anonymous(&self.config.remote_url)?;
+        // Submodules may be out of sync even if the repository itself is up to date
+        let stale = match config.submodules {
+            Submodules::Disabled => Vec::new(),
+            Submodules::Enabled | Submodules::Recursive => tokio::task::block_in_place(|| {
+                stale_submodules(&repository, config.filter().as_ref())
+            })
+            .context("Failed to check submodules")?,
+        };
 
-        let mut remote_cb = git2::RemoteCallbacks::new();
-        match &self.config.credentials {
-            None => {}
+        // Releases are never modified in place
+        let dirty = match (&config.releases, &latest_obj) {
+            (None, Some(_)) => tokio::task::block_in_place(|| local_changes(&repository, config))
+                .context("Failed to check for local changes")?,
+            _ => Vec::new(),
+        };
+        self.lock_state().dirty = dirty.clone();
+
+        // If the remote ref is the same as the local HEAD ref, we're up to date
+        if let Some(ref latest_obj) = latest_obj {
+            if latest_obj.id() == target_obj.id() && stale.is_empty() {
+                debug!("Already up to date");
+                let mut state = self.lock_state();
+                state.pending = None;
+                state.commit = Some(latest_obj.id().to_string());
+                state.tag = tag;
+                return Ok(None);
+            }
+        }
+
+        if !dirty.is_empty() && config.local_changes == LocalChanges::Refuse {
+            anyhow::bail!(
+                "Refusing to update to {}: {} file(s) changed locally",
+                target_obj.id(),
+                dirty.len()
+            );
+        }
+
+        if let Some(ref keys) = keys {
+            tokio::task::block_in_place(|| keys.verify(&repository, target_obj.id()))
+                .with_context(|| format!("Refusing to check out {}", target_obj.id()))?;
+            debug!("Verified signature of {}", target_obj.id());
+        }
+
+        let mut files = tokio::task::block_in_place(|| {
+            changed_files(&repository, latest_obj.as_ref(), &target_obj)
+        })
+        .context("Failed to diff against target ref")?;
+
+        if !stale.is_empty() {
+            debug!("Found stale submodules: {:?}", stale);
+            files.extend(stale);
+            files.sort();
+            files.dedup();
+        }
+
+        // Changes outside of the checked out paths are irrelevant
+        if let Some(filter) = config.filter() {
+            files.retain(|file| filter.matches(file));
+        }
 
-            Some(Credentials::Password(password)) => {
-                let plain_username = password.username.clone();
-                let plain_password = password.password.load().await?;
+        Ok(Some(Change {
+            old: latest_obj.map(|obj| obj.id()),
+            new: target_obj.id(),
+            tag,
+            files,
+        }))
+    }
+
+    /// Checks out the new commit of a fetched change.
+    pub async fn checkout(&self, config: &Config, change: &Change) -> Result<()> {
+        let result = self.checkout_change(config, change).await;
+        self.backoff_on(&result);
+        result
+    }
+
+    async fn checkout_change(&self, config: &Config, change: &Change) -> Result<()> {
+        let auth = SubmoduleAuth::load(config).await?;
+        let lfs = fetch_lfs(config, change.new).await?;
+
+        tokio::task::block_in_place(|| checkout_commit(config, &auth, lfs.as_ref(), change.new))?;
+
+        info!("Updated to {}", change.new);
+        let mut state = self.lock_state();
+        state.commit = Some(change.new.to_string());
+        state.tag = change.tag.clone();
+        state.last_changed = Some(SystemTime::now());
+        state.pending = None;
+
+        Ok(())
+    }
+
+    /// Writes the tree of a commit to the given directory without touching the working tree.
+    pub fn export(&self, config: &Config, commit: git2::Oid, dir: &Path) -> Result<()> {
+        tokio::task::block_in_place(|| {
+            let repository = git2::Repository::open(config.repository_path())?;
+            export_tree(&repository, commit, dir, config.filter().as_ref())
+        })
+        .with_context(|| format!("Failed to export commit: {}", commit))
+    }
+
+    /// Directory containing the checked out tree of the commit.
+    pub fn worktree(&self, config: &Config, commit: git2::Oid) -> PathBuf {
+        match config.releases {
+            Some(_) => release::path(&config.path, commit),
+            None => config.path.clone(),
+        }
+    }
+
+    /// Checks out the commit replaced by the change again and rejects the changed commit.
+    ///
+    /// Returns the change reverting the given one or `None` if there is no previous commit.
+    pub async fn rollback(&self, config: &Config, change: &Change) -> Result<Option<Change>> {
+        let Some(old) = change.old else {
+            return Ok(None);
+        };
+
+        let auth = SubmoduleAuth::load(config).await?;
+        let lfs = fetch_lfs(config, old).await?;
+
+        tokio::task::block_in_place(|| checkout_commit(config, &auth, lfs.as_ref(), old))?;
+
+        info!("Rolled back to {}", old);
+
+        // The rollback is done already, so a failure only lets the commit be tried again
+        let rejected = change.new.to_string();
+        if let Err(err) = History::new(config).set_rejected(Some(&rejected)).await {
+            warn!("Failed to record rejected commit: {:#}", err);
+        }
+
+        let mut state = self.lock_state();
+        state.commit = Some(old.to_string());
+        state.tag = None;
+        state.last_changed = Some(SystemTime::now());
+        state.rejected_commit = Some(rejected);
+
+        Ok(Some(Change {
+            old: Some(change.new),
+            new: old,
+            tag: None,
+            files: change.files.clone(),
+        }))
+    }
+
+    /// Keeps the change fetched until the next deploy window opens.
+    pub fn defer(&self, change: &Change) {
+        self.lock_state().pending = Some(change.new.to_string());
+    }
+
+    pub fn pending(&self) -> bool {
+        self.lock_state().pending.is_some()
+    }
+
+    /// Lists the changes checked out so far, oldest first.
+    pub async fn history(&self) -> Result<Vec<history::Entry>> {
+        History::new(&self.config()).entries().await
+    }
+
+    /// Appends a checked out change to the history.
+    pub async fn record_history(
+        &self,
+        config: &Config,
+        change: &Change,
+        trigger: &str,
+        hook: Option<history::HookResult>,
+        duration: Duration,
+    ) {
+        let entry = history::Entry {
+            id: 0,
+            time: SystemTime::now(),
+            old: change.old.map(|old| old.to_string()),
+            new: change.new.to_string(),
+            tag: change.tag.clone(),
+            trigger: trigger.to_owned(),
+            hook,
+            duration: duration.as_secs_f64(),
+        };
+
+        // The change is checked out already, so a failure must not fail the update
+        if let Err(err) = History::new(config).append(entry).await {
+            warn!("Failed to record history: {:#}", err);
+        }
+    }
+
+    /// Pins the commit until released, replacing the configured pin.
+    pub async fn pin(&self, commit: &str) -> Result<()> {
+        let history = History::new(&self.config());
+        history.set_pin(Some(commit)).await?;
+
+        info!("Pinned to {}", commit);
+        self.lock_state().pin = Some(commit.to_owned());
+
+        // Checking out a rejected commit again is requested explicitly
+        history.set_rejected(None).await?;
+        self.lock_state().rejected_commit = None;
+
+        Ok(())
+    }
+
+    /// Removes the pin set by a rollback.
+    pub async fn release(&self) -> Result<()> {
+        History::new(&self.config()).set_pin(None).await?;
+
+        info!("Released pin");
+        self.lock_state().pin = None;
+
+        Ok(())
+    }
+
+    /// Records the outcome of an update including the hooks.
+    pub fn record(&self, result: &Result<()>) {
+        let mut state = self.lock_state();
+        match result {
+            Ok(()) => {
+                state.last_error = None;
+                state.failures = 0;
+                state.failing = false;
+                state.error_chain = Vec::new();
+            }
+            Err(err) => {
+                state.last_error = Some(format!("{:#}", err));
+                state.failures += 1;
+                state.failing = true;
+                state.error_chain = err.chain().map(ToString::to_string).collect();
+            }
+        }
+        drop(state);
+
+        // Failing scripts do not slow down the checks, only an unreachable or broken remote does
+        let mut backoff = self.backoff.lock().expect("backoff lock poisoned");
+        if !std::mem::take(&mut backoff.failed) {
+            *backoff = Backoff::default();
+            return;
+        }
+
+        backoff.failures += 1;
+
+        let config = self.config();
+        if let Some(period) = config.period() {
+            backoff.skips = config.backoff.skips(period, backoff.failures);
+            if backoff.skips > 0 {
+                warn!(
+                    "Backing off for {} after {} failures",
+                    humantime::format_duration(period * (backoff.skips + 1)),
+                    backoff.failures
+                );
+            }
+        }
+    }
+
+    fn backoff_on<T>(&self, result: &Result<T>) {
+        if result.is_err() {
+            self.backoff.lock().expect("backoff lock poisoned").failed = true;
+        }
+    }
 
+    pub fn record_hook(&self, status: HookStatus) {
+        self.lock_state().last_hook = Some(status);
+    }
+
+    pub fn record_failed_change(&self, change: Change) {
+        self.lock_state().failed_change = Some(change);
+    }
+
+    pub fn take_failed_change(&self) -> Option<Change> {
+        self.lock_state().failed_change.take()
+    }
+
+    pub fn state(&self) -> RepoState {
+        self.lock_state().clone()
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, RepoState> {
+        self.state.lock().expect("state lock poisoned")
+    }
+}
+
+/// Lists the tags of the remote and picks the highest one matching the selector.
+fn latest_tag(remote: &mut git2::Remote, auth: &Auth, selector: &TagSelector) -> Result<String> {
+    let connection = remote
+        .connect_auth(git2::Direction::Fetch, Some(auth.callbacks()), None)
+        .context("Failed to list remote tags")?;
+
+    let tags: Vec<_> = connection
+        .list()?
+        .iter()
+        .filter_map(|head| head.name().strip_prefix("refs/tags/"))
+        .filter(|tag| !tag.ends_with("^{}"))
+        .map(str::to_owned)
+        .collect();
+
+    selector
+        .select(tags.iter().map(String::as_str))
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow::anyhow!("No remote tag matching {}", selector))
+}
+
+/// Looks up the pinned commit and fetches it explicitly if not reachable from the remote ref.
+fn pinned_commit<'r>(
+    repository: &'r git2::Repository,
+    remote: &mut git2::Remote,
+    auth: &Auth,
+    pin: &str,
+    depth: Option<u32>,
+) -> Result<git2::Object<'r>> {
+    let find = || {
+        repository
+            .revparse_single(pin)
+            .and_then(|obj| obj.peel(git2::ObjectType::Commit))
+    };
+
+    if let Ok(obj) = find() {
+        return Ok(obj);
+    }
+
+    debug!("Fetching pinned commit {}", pin);
+    remote
+        .fetch(
+            &[pin],
+            Some(
+                git2::FetchOptions::new()
+                    .depth(fetch_depth(repository, depth))
+                    .remote_callbacks(auth.callbacks()),
+            ),
+            None,
+        )
+        .with_context(|| format!("Failed to fetch pinned commit: {}", pin))?;
+
+    find().with_context(|| format!("Pinned commit not found: {}", pin))
+}
+
+/// Depth passed to fetches, shallow repositories are completed if the depth is no longer limited.
+fn fetch_depth(repository: &git2::Repository, depth: Option<u32>) -> i32 {
+    match depth {
+        Some(depth) => i32::try_from(depth).unwrap_or(i32::MAX),
+        None if repository.is_shallow() => UNSHALLOW,
+        None => 0,
+    }
+}
+
+/// Downloads the LFS objects referred by the commit which are missing locally.
+async fn fetch_lfs(
+    config: &Config,
+    commit: git2::Oid,
+) -> Result<Option<(lfs::Store, Vec<lfs::Pointer>)>> {
+    if !config.lfs {
+        return Ok(None);
+    }
+
+    let (store, mut pointers) = tokio::task::block_in_place(|| -> Result<_> {
+        let repository = git2::Repository::open(config.repository_path())?;
+        Ok((
+            lfs::Store::new(&repository),
+            lfs::pointers(&repository, commit)?,
+        ))
+    })
+    .context("Failed to find LFS pointers")?;
+
+    if let Some(filter) = config.filter() {
+        pointers.retain(|pointer| filter.matches(&pointer.path));
+    }
+
+    let endpoint = match config.lfs_url {
+        Some(ref lfs_url) => lfs_url.clone(),
+        None => lfs::endpoint(&config.remote_url)?,
+    };
+
+    // Only password credentials apply to the HTTP based LFS API
+    let login = match config.credentials {
+        Some(Credentials::Password(ref password)) => Some(lfs::Login {
+            username: password.username.clone(),
+            password: password.password.load().await?,
+        }),
+        _ => None,
+    };
+
+    lfs::fetch(&store, &endpoint, login.as_ref(), &pointers).await?;
+
+    Ok(Some((store, pointers)))
+}
+
+/// Checks out the commit either as new release or by resetting the local HEAD ref in place.
+fn checkout_commit(
+    config: &Config,
+    auth: &SubmoduleAuth,
+    lfs: Option<&(lfs::Store, Vec<lfs::Pointer>)>,
+    commit: git2::Oid,
+) -> Result<()> {
+    let mut repository = git2::Repository::open(config.repository_path())?;
+    let filter = config.filter();
+
+    if let Some(ref releases) = config.releases {
+        return release::deploy(
+            &repository,
+            &config.path,
+            commit,
+            releases.keep,
+            filter.as_ref(),
+            lfs,
+        );
+    }
+
+    if repository.head().is_ok() {
+        let files =
+            local_changes(&repository, config).context("Failed to check for local changes")?;
+        if !files.is_empty() {
+            match config.local_changes {
+                LocalChanges::Discard => {
+                    warn!("Discarding local changes in {} file(s)", files.len())
+                }
+                LocalChanges::Refuse => anyhow::bail!(
+                    "Refusing to check out {}: {} file(s) changed locally",
+                    commit,
+                    files.len()
+                ),
+                LocalChanges::Stash => stash(&mut repository, &files)?,
+            }
+        }
+    }
+
+    let target_obj = repository
+        .find_object(commit, None)
+        .with_context(|| format!("Failed to find commit: {}", commit))?;
+
+    match filter {
+        None => repository
+            .reset(
+                &target_obj,
+                git2::ResetType::Hard,
+                Some(
+                    git2::build::CheckoutBuilder::new()
+                        .force()
+                        .remove_untracked(true),
+                ),
+            )
+            .map_err(Into::into),
+        Some(ref filter) => sparse_reset(&repository, &target_obj, filter),
+    }
+    .with_context(|| format!("Failed to reset repo to target ref: {}", commit))?;
+
+    if let Some((store, pointers)) = lfs {
+        store.smudge(&config.path, pointers)?;
+    }
+
+    match config.submodules {
+        Submodules::Disabled => Ok(()),
+        Submodules::Enabled => update_submodules(&repository, auth, filter.as_ref(), false),
+        Submodules::Recursive => update_submodules(&repository, auth, filter.as_ref(), true),
+    }
+}
+
+/// Lists the files in the working tree which differ from HEAD and would be lost by a reset.
+///
+/// Files outside of the configured paths and LFS objects replacing their pointer files are ignored.
+fn local_changes(repository: &git2::Repository, config: &Config) -> Result<Vec<PathBuf>> {
+    let pointers: HashSet<_> = if config.lfs {
+        let head = repository.head()?.peel_to_commit()?;
+        lfs::pointers(repository, head.id())?
+            .into_iter()
+            .map(|pointer| pointer.path)
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let filter = config.filter();
+
+    let statuses = repository.statuses(Some(
+        git2::StatusOptions::new()
+            .include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .exclude_submodules(true),
+    ))?;
+
+    Ok(statuses
+        .iter()
+        .map(|entry| PathBuf::from(String::from_utf8_lossy(entry.path_bytes()).as_ref()))
+        .filter(|path| filter.as_ref().is_none_or(|filter| filter.matches(path)))
+        .filter(|path| !pointers.contains(path))
+        .collect())
+}
+
+/// Saves the changed files as stash entry and resets them to HEAD.
+fn stash(repository: &mut git2::Repository, files: &[PathBuf]) -> Result<()> {
+    let stasher = repository
+        .signature()
+        .or_else(|_| git2::Signature::now("pullomatic", "pullomatic@localhost"))?;
+
+    let mut options = git2::StashSaveOptions::new(stasher);
+    options.flags(Some(git2::StashFlags::INCLUDE_UNTRACKED));
+    for file in files {
+        options.pathspec(file.as_path());
+    }
+
+    let stash = repository
+        .stash_save_ext(Some(&mut options))
+        .context("Failed to stash local changes")?;
+
+    warn!(
+        "Stashed local changes in {} file(s) as {}",
+        files.len(),
+        stash
+    );
+
+    Ok(())
+}
+
+/// Resets the local HEAD ref to the target but only checks out the files matching the filter.
+fn sparse_reset(
+    repository: &git2::Repository,
+    target_obj: &git2::Object,
+    filter: &sparse::Filter,
+) -> Result<()> {
+    // Files of the previous checkout must be covered as well to remove deleted ones
+    let (mut files, excluded) = match repository.head().and_then(|head| head.peel_to_tree()) {
+        Ok(tree) => (filter.files(&tree)?, filter.excluded(&tree)?),
+        Err(_) => (Vec::new(), Vec::new()),
+    };
+    files.extend(filter.files(&target_obj.peel_to_tree()?)?);
+
+    repository.reset(target_obj, git2::ResetType::Mixed, None)?;
+
+    // An empty path list would check out everything
+    if !files.is_empty() {
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout
+            .force()
+            .remove_untracked(true)
+            .disable_pathspec_match(true);
+        for file in files {
+            checkout.path(file);
+        }
+
+        repository.checkout_head(Some(&mut checkout))?;
+    }
+
+    // Files checked out before the filter was narrowed are not touched by the checkout
+    let workdir = repository
+        .workdir()
+        .context("Repository has no working tree")?;
+    for file in excluded {
+        remove_stale(workdir, &file).with_context(|| {
+            format!("Failed to remove file outside of paths: {}", file.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Removes the file from the working tree along with the directories left empty.
+///
+/// Submodules outside of the filter are kept.
+fn remove_stale(workdir: &Path, file: &Path) -> std::io::Result<()> {
+    let path = workdir.join(file);
+    match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if !metadata.is_dir() => std::fs::remove_file(&path)?,
+        Ok(_) => return Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+
+    debug!("Removed file outside of paths: {}", file.display());
+
+    for dir in file.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || std::fs::remove_dir(workdir.join(dir)).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Initializes, fetches and checks out all submodules at the commits recorded in HEAD.
+fn update_submodules(
+    repository: &git2::Repository,
+    auth: &SubmoduleAuth,
+    filter: Option<&sparse::Filter>,
+    recursive: bool,
+) -> Result<()> {
+    for mut submodule in repository.submodules()? {
+        if filter.is_some_and(|filter| !filter.matches(submodule.path())) {
+            continue;
+        }
+
+        let name = submodule.name().unwrap_or_default().to_owned();
+        debug!("Updating submodule {}", name);
+
+        let mut fetch = git2::FetchOptions::new();
+        fetch.remote_callbacks(auth.get(&name).callbacks());
+
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force();
+
+        submodule
+            .update(
+                true,
+                Some(
+                    git2::SubmoduleUpdateOptions::new()
+                        .fetch(fetch)
+                        .checkout(checkout),
+                ),
+            )
+            .with_context(|| format!("Failed to update submodule: {}", name))?;
+
+        if recursive {
+            let repository = submodule
+                .open()
+                .with_context(|| format!("Failed to open submodule: {}", name))?;
+            update_submodules(&repository, auth, None, true)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists submodules whose checked out commit differs from the one recorded in HEAD.
+fn stale_submodules(
+    repository: &git2::Repository,
+    filter: Option<&sparse::Filter>,
+) -> Result<Vec<PathBuf>, git2::Error> {
+    Ok(repository
+        .submodules()?
+        .iter()
+        // Submodules outside of the paths are never checked out
+        .filter(|submodule| filter.is_none_or(|filter| filter.matches(submodule.path())))
+        .filter(|submodule| {
+            submodule.head_id().is_some() && submodule.workdir_id() != submodule.head_id()
+        })
+        .map(|submodule| submodule.path().to_owned())
+        .collect())
+}
+
+/// Writes the tree of the commit to the given directory without touching the index.
+pub fn export_tree(
+    repository: &git2::Repository,
+    commit: git2::Oid,
+    dir: &Path,
+    filter: Option<&sparse::Filter>,
+) -> Result<(), git2::Error> {
+    let target_obj = repository.find_object(commit, None)?;
+
+    let mut checkout = git2::build::CheckoutBuilder::new();
+    checkout.target_dir(dir).update_index(false).force();
+
+    if let Some(filter) = filter {
+        let files = filter.files(&target_obj.peel_to_tree()?)?;
+
+        // An empty path list would check out everything
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        checkout.disable_pathspec_match(true);
+        for file in files {
+            checkout.path(file);
+        }
+    }
+
+    repository.checkout_tree(&target_obj, Some(&mut checkout))
+}
+
+/// Lists all files which differ between the trees of both commits.
+fn changed_files(
+    repository: &git2::Repository,
+    old: Option<&git2::Object>,
+    new: &git2::Object,
+) -> Result<Vec<PathBuf>, git2::Error> {
+    let old_tree = old.map(|old| old.peel_to_tree()).transpose()?;
+    let new_tree = new.peel_to_tree()?;
+
+    let diff = repository.diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), None)?;
+
+    let files: BTreeSet<_> = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .map(Path::to_owned)
+        .collect();
+
+    Ok(files.into_iter().collect())
+}
+
+/// Credentials with all secrets loaded.
+enum Auth {
+    None,
+    Password {
+        username: String,
+        password: String,
+    },
+    Ssh {
+        username: String,
+        public_key: Option<String>,
+        private_key: String,
+        passphrase: Option<String>,
+    },
+}
+
+/// Credentials for submodules with all secrets loaded.
+struct SubmoduleAuth {
+    default: Auth,
+    named: HashMap<String, Auth>,
+}
+
+impl SubmoduleAuth {
+    async fn load(config: &Config) -> Result<Self> {
+        if config.submodules == Submodules::Disabled {
+            return Ok(Self {
+                default: Auth::None,
+                named: HashMap::new(),
+            });
+        }
+
+        let mut named = HashMap::new();
+        for (name, credentials) in &config.submodule_credentials {
+            named.insert(name.clone(), Auth::load(Some(credentials)).await?);
+        }
+
+        Ok(Self {
+            default: Auth::load(config.credentials.as_ref()).await?,
+            named,
+        })
+    }
+
+    fn get(&self, name: &str) -> &Auth {
+        self.named.get(name).unwrap_or(&self.default)
+    }
+}
+
+impl Auth {
+    async fn load(credentials: Option<&Credentials>) -> Result<Self> {
+        Ok(match credentials {
+            None => Self::None,
+
+            Some(Credentials::Password(password)) => Self::Password {
+                username: password.username.clone(),
+                password: password.password.load().await?,
+            },
+
+            Some(Credentials::Ssh(ssh)) => Self::Ssh {
+                username: ssh.username.clone(),
+                public_key: ssh.public_key.clone(),
+                private_key: ssh.private_key.load().await?,
+                passphrase: match ssh.passphrase {
+                    Some(ref passphrase) => Some(passphrase.load().await?),
+                    None => None,
+                },
+            },
+        })
+    }
+
+    fn callbacks(&self) -> git2::RemoteCallbacks<'_> {
+        let mut remote_cb = git2::RemoteCallbacks::new();
+        match self {
+            Self::None => {}
+
+            Self::Password {
+                username: plain_username,
+                password: plain_password,
+            } => {
                 remote_cb.credentials(move |url, username, allowed| {
                     trace!("cred: url = {:?}", url);
                     trace!("cred: username = {:?}", username);
                     trace!("cred: allowed = {:?}", allowed);
 
                     if allowed.contains(git2::CredentialType::USERNAME) {
-                        return git2::Cred::username(&plain_username);
+                        return git2::Cred::username(plain_username);
                     }
 
                     if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
-                        return git2::Cred::userpass_plaintext(&plain_username, &plain_password);
+                        return git2::Cred::userpass_plaintext(plain_username, plain_password);
                     }
 
                     Err(git2::Error::from_str("Unsupported authentication"))
                 });
             }
 
-            Some(Credentials::Ssh(ssh)) => {
-                let ssh_username = ssh.username.as_str();
-
-                let ssh_private_key = ssh.private_key.load().await?;
-                let ssh_public_key = ssh.public_key.as_ref().map(String::as_ref);
-
-                let ssh_passphrase = match ssh.passphrase {
-                    Some(ref passphrase) => Some(passphrase.load().await?),
-                    None => None,
-                };
-
+            Self::Ssh {
+                username: ssh_username,
+                public_key: ssh_public_key,
+                private_key: ssh_private_key,
+                passphrase: ssh_passphrase,
+            } => {
                 remote_cb.credentials(move |url, username, allowed| {
                     trace!("cred: url = {:?}", url);
                     trace!("cred: username = {:?}", username);
@@ -101,9 +1195,9 @@ impl Repo {
                     if allowed.contains(git2::CredentialType::SSH_KEY) {
                         return git2::Cred::ssh_key_from_memory(
                             ssh_username,
-                            ssh_public_key,
-                            ssh_private_key.as_ref(),
-                            ssh_passphrase.as_ref().map(String::as_ref),
+                            ssh_public_key.as_deref(),
+                            ssh_private_key,
+                            ssh_passphrase.as_deref(),
                         );
                     }
 
@@ -112,66 +1206,113 @@ impl Repo {
             }
         }
 
-        debug!("Fetching data from remote");
-        tokio::task::block_in_place(|| {
-            // Fetch the remote branch head ref into our target ref
-            remote
-                .fetch(
-                    &[&format!("+{}:{}", self.config.remote_ref(), TARGET_REF)],
-                    Some(
-                        git2::FetchOptions::new()
-                            .prune(git2::FetchPrune::On)
-                            .download_tags(git2::AutotagOption::None)
-                            .remote_callbacks(remote_cb),
-                    ),
-                    None,
-                )
-                .with_context(|| {
-                    format!(
-                        "Failed to fetch data from remote: {}",
-                        self.config.remote_ref()
-                    )
-                })
-        })?;
-        debug!("Fetched data from remote");
+        remote_cb
+    }
+}
 
-        let latest_obj = repository.revparse_single("HEAD").ok();
-        let target_obj = repository
-            .revparse_single(TARGET_REF)
-            .expect("target ref fetched");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::Provider;
 
-        // If the remote ref is the same as the local HEAD ref, we're up to date
-        if let Some(ref latest_obj) = latest_obj {
-            if latest_obj.id() == target_obj.id() {
-                debug!("Already up to date");
-                return Ok(false);
-            }
-        }
+    fn repo() -> Repo {
+        let config = serde_yaml::from_str(
+            "path: /nonexistent\nremote_url: /nonexistent\nremote_branch: main\ninterval: { interval: 1m }",
+        )
+        .unwrap();
+        Repo::new("test".to_owned(), config)
+    }
 
-        tokio::task::block_in_place(|| {
-            // Reset the local HEAD ref to the remote ref, and force a checkout
-            repository
-                .reset(
-                    &target_obj,
-                    git2::ResetType::Hard,
-                    Some(
-                        git2::build::CheckoutBuilder::new()
-                            .force()
-                            .remove_untracked(true),
-                    ),
-                )
-                .with_context(|| format!("Failed to reset repo to target ref: {}", target_obj.id()))
-        })?;
+    #[test]
+    fn enqueue_coalesces() {
+        let repo = repo();
+
+        assert!(repo.enqueue(Trigger::Interval));
+        assert_eq!(repo.schedule(), Schedule::Queued(Trigger::Interval));
+
+        // Already queued, an explicit trigger replaces the periodic one
+        assert!(!repo.enqueue(Trigger::Webhook(Provider::Plain)));
+        assert!(!repo.enqueue(Trigger::Interval));
+        assert_eq!(
+            repo.schedule(),
+            Schedule::Queued(Trigger::Webhook(Provider::Plain))
+        );
+
+        assert_eq!(repo.start(), Trigger::Webhook(Provider::Plain));
+        assert_eq!(repo.schedule(), Schedule::Running);
+
+        assert!(!repo.finish());
+        assert_eq!(repo.schedule(), Schedule::Idle);
+    }
+
+    #[test]
+    fn enqueue_while_running() {
+        let repo = repo();
+
+        assert!(repo.enqueue(Trigger::Interval));
+        assert_eq!(repo.start(), Trigger::Interval);
+
+        // Requested again once the running update has finished
+        assert!(!repo.enqueue(Trigger::Interval));
+        assert!(!repo.enqueue(Trigger::Manual));
+        assert_eq!(repo.schedule(), Schedule::Pending(Trigger::Manual));
 
-        info!("Updated to {}", target_obj.id());
-        state.last_changed = now;
+        assert!(repo.finish());
+        assert_eq!(repo.schedule(), Schedule::Queued(Trigger::Manual));
+        assert_eq!(repo.start(), Trigger::Manual);
+        assert!(!repo.finish());
+    }
+
+    #[test]
+    fn enqueue_keeps_forced() {
+        let repo = repo();
+
+        assert!(repo.enqueue(Trigger::Deploy));
+        assert!(!repo.enqueue(Trigger::Manual));
+        assert!(!repo.enqueue(Trigger::Window));
+        assert!(!repo.enqueue(Trigger::Rollback));
+        assert!(!repo.enqueue(Trigger::Manual));
+        assert_eq!(repo.schedule(), Schedule::Queued(Trigger::Rollback));
+    }
+
+    #[test]
+    #[should_panic(expected = "repo not queued")]
+    fn start_idle() {
+        repo().start();
+    }
 
-        Ok(true)
+    #[test]
+    #[should_panic(expected = "repo not queued")]
+    fn start_running() {
+        let repo = repo();
+        repo.enqueue(Trigger::Manual);
+        repo.start();
+        repo.start();
     }
 
-    #[allow(unused)]
-    pub async fn last_checked(&self) -> Option<Instant> {
-        let state = self.state.lock().await;
-        state.last_checked
+    #[tokio::test]
+    async fn enqueue_backing_off() {
+        let repo = repo();
+        let failed = || -> Result<()> { Err(anyhow::anyhow!("Remote unavailable")) };
+
+        for _ in 0..2 {
+            repo.backoff_on(&failed());
+            repo.record(&failed());
+        }
+
+        // One periodic trigger is skipped after the second failure
+        assert!(!repo.enqueue(Trigger::Interval));
+        assert_eq!(repo.schedule(), Schedule::Idle);
+        assert!(repo.enqueue(Trigger::Interval));
+        assert_eq!(repo.start(), Trigger::Interval);
+        repo.finish();
+
+        // Failing scripts reset the backoff
+        for _ in 0..2 {
+            repo.backoff_on(&failed());
+            repo.record(&failed());
+        }
+        repo.record(&failed());
+        assert!(repo.enqueue(Trigger::Interval));
     }
 }
@@ -0,0 +1,178 @@
+//! Restricts checkouts to a subset of paths.
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+
+/// Include and exclude patterns, excludes are prefixed with `!`.
+///
+/// A pattern matches a path itself and everything below it.
+#[derive(Debug)]
+pub struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let mut include = GlobSetBuilder::new();
+        let mut exclude = GlobSetBuilder::new();
+        let mut includes = false;
+
+        for pattern in patterns {
+            let (set, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (&mut exclude, pattern),
+                None => {
+                    includes = true;
+                    (&mut include, pattern.as_str())
+                }
+            };
+
+            let pattern = pattern.trim_matches('/');
+            for pattern in [pattern.to_owned(), format!("{}/**", pattern)] {
+                set.add(
+                    GlobBuilder::new(&pattern)
+                        .literal_separator(true)
+                        .build()
+                        .with_context(|| format!("Invalid path pattern: {}", pattern))?,
+                );
+            }
+        }
+
+        Ok(Self {
+            include: if includes {
+                Some(include.build()?)
+            } else {
+                None
+            },
+            exclude: exclude.build()?,
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(path))
+            && !self.exclude.is_match(path)
+    }
+
+    /// Lists all files and submodules in the tree matching the filter.
+    pub fn files(&self, tree: &git2::Tree) -> Result<Vec<PathBuf>, git2::Error> {
+        self.walk(tree, true)
+    }
+
+    /// Lists all files and submodules in the tree outside of the filter.
+    pub fn excluded(&self, tree: &git2::Tree) -> Result<Vec<PathBuf>, git2::Error> {
+        self.walk(tree, false)
+    }
+
+    fn walk(&self, tree: &git2::Tree, matching: bool) -> Result<Vec<PathBuf>, git2::Error> {
+        let mut files = Vec::new();
+
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() == Some(git2::ObjectType::Tree) {
+                return git2::TreeWalkResult::Ok;
+            }
+
+            let path = Path::new(root).join(String::from_utf8_lossy(entry.name_bytes()).as_ref());
+            if self.matches(&path) == matching {
+                files.push(path);
+            }
+
+            git2::TreeWalkResult::Ok
+        })?;
+
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(patterns: &[&str]) -> Filter {
+        Filter::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn include() {
+        let filter = filter(&["config", "/etc/*.conf/"]);
+
+        assert!(filter.matches(Path::new("config")));
+        assert!(filter.matches(Path::new("config/app.yaml")));
+        assert!(filter.matches(Path::new("config/nested/app.yaml")));
+        assert!(filter.matches(Path::new("etc/nginx.conf")));
+        assert!(filter.matches(Path::new("etc/nginx.conf/site")));
+
+        assert!(!filter.matches(Path::new("configuration")));
+        assert!(!filter.matches(Path::new("src/config")));
+        assert!(!filter.matches(Path::new("etc/sub/nginx.conf")));
+    }
+
+    #[test]
+    fn exclude() {
+        let filter = filter(&["config", "!config/secret", "!**/*.bak"]);
+
+        assert!(filter.matches(Path::new("config/app.yaml")));
+        assert!(!filter.matches(Path::new("config/secret")));
+        assert!(!filter.matches(Path::new("config/secret/key")));
+        assert!(!filter.matches(Path::new("config/app.yaml.bak")));
+        assert!(!filter.matches(Path::new("README.md")));
+    }
+
+    #[test]
+    fn exclude_only() {
+        let filter = filter(&["!docs"]);
+
+        assert!(filter.matches(Path::new("README.md")));
+        assert!(filter.matches(Path::new("src/main.rs")));
+        assert!(!filter.matches(Path::new("docs/index.md")));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Filter::new(&["config/[".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn files() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = git2::Repository::init(dir.path()).unwrap();
+
+        let mut index = repository.index().unwrap();
+        for path in [
+            "README.md",
+            "config/app.yaml",
+            "config/secret",
+            "src/main.rs",
+        ] {
+            let blob = repository.blob(path.as_bytes()).unwrap();
+            let entry = git2::IndexEntry {
+                ctime: git2::IndexTime::new(0, 0),
+                mtime: git2::IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id: blob,
+                flags: 0,
+                flags_extended: 0,
+                path: path.as_bytes().to_vec(),
+            };
+            index.add(&entry).unwrap();
+        }
+        let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let filter = filter(&["config", "!config/secret", "README.md"]);
+        assert_eq!(
+            filter.files(&tree).unwrap(),
+            [PathBuf::from("README.md"), PathBuf::from("config/app.yaml")]
+        );
+        assert_eq!(
+            filter.excluded(&tree).unwrap(),
+            [PathBuf::from("config/secret"), PathBuf::from("src/main.rs")]
+        );
+    }
+}
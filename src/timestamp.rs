@@ -0,0 +1,34 @@
+//! Serializes wall-clock timestamps as RFC 3339 strings.
+
+use serde::{Deserialize, Deserializer, Serializer};
+use std::time::SystemTime;
+
+pub fn serialize<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(&humantime::format_rfc3339_seconds(*time))
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    humantime::parse_rfc3339(&time).map_err(serde::de::Error::custom)
+}
+
+pub mod option {
+    use serde::Serializer;
+    use std::time::SystemTime;
+
+    pub fn serialize<S>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match time {
+            Some(time) => super::serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+}
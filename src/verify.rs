@@ -0,0 +1,455 @@
+//! Verification of commit signatures against trusted keys.
+
+use crate::config::Verify;
+use anyhow::{anyhow, bail, Context, Result};
+use pgp::composed::{Deserializable, DetachedSignature, SignedPublicKey};
+use ssh_key::{HashAlg, PublicKey, SshSig};
+
+/// Namespace used by git for SSH signatures
+const SSH_NAMESPACE: &str = "git";
+
+#[derive(Debug)]
+struct SshKey {
+    key: PublicKey,
+
+    // Committer emails allowed to use the key, any if not restricted
+    principals: Option<Vec<String>>,
+}
+
+impl SshKey {
+    fn allows(&self, email: Option<&str>) -> bool {
+        let Some(ref principals) = self.principals else {
+            return true;
+        };
+
+        let Some(email) = email else {
+            return false;
+        };
+
+        principals.iter().any(|pattern| matches(pattern, email))
+    }
+}
+
+/// Trusted keys with all key files loaded.
+#[derive(Debug)]
+pub struct Keys {
+    ssh: Vec<SshKey>,
+    openpgp: Vec<SignedPublicKey>,
+}
+
+impl Keys {
+    pub async fn load(verify: &Verify) -> Result<Self> {
+        let mut ssh = Vec::new();
+        let mut openpgp = Vec::new();
+
+        for key in &verify.ssh_keys {
+            ssh.push(SshKey {
+                key: PublicKey::from_openssh(key)
+                    .with_context(|| format!("Invalid SSH key: {}", key))?,
+                principals: None,
+            });
+        }
+
+        if let Some(ref path) = verify.allowed_signers {
+            let input = tokio::fs::read_to_string(path).await.with_context(|| {
+                format!("Failed to read allowed signers file: {}", path.display())
+            })?;
+
+            ssh.extend(
+                allowed_signers(&input)
+                    .with_context(|| format!("Invalid allowed signers file: {}", path.display()))?,
+            );
+        }
+
+        for path in &verify.openpgp_keys {
+            let input = tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read OpenPGP key file: {}", path.display()))?;
+
+            openpgp.push(
+                openpgp_key(&input)
+                    .with_context(|| format!("Invalid OpenPGP key file: {}", path.display()))?,
+            );
+        }
+
+        if ssh.is_empty() && openpgp.is_empty() {
+            bail!("No trusted keys configured");
+        }
+
+        Ok(Self { ssh, openpgp })
+    }
+
+    /// Checks that the commit is signed by one of the trusted keys.
+    pub fn verify(&self, repository: &git2::Repository, commit: git2::Oid) -> Result<()> {
+        let (signature, data) = repository
+            .extract_signature(&commit, None)
+            .map_err(|_| anyhow!("Commit is not signed"))?;
+
+        let signature = std::str::from_utf8(&signature).context("Malformed signature")?;
+
+        if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+            let committer = repository
+                .find_commit(commit)?
+                .committer()
+                .email()
+                .map(str::to_owned);
+            self.verify_ssh(signature, &data, committer.as_deref())
+        } else if signature.starts_with("-----BEGIN PGP SIGNATURE-----") {
+            self.verify_openpgp(signature, &data)
+        } else {
+            bail!("Unsupported signature format")
+        }
+    }
+
+    fn verify_ssh(&self, signature: &str, data: &[u8], committer: Option<&str>) -> Result<()> {
+        let signature = SshSig::from_pem(signature).context("Malformed SSH signature")?;
+
+        let key = self
+            .ssh
+            .iter()
+            .filter(|key| key.key.key_data() == signature.public_key())
+            .find(|key| key.allows(committer))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Signed by untrusted SSH key {}",
+                    signature.public_key().fingerprint(HashAlg::Sha256)
+                )
+            })?;
+
+        key.key
+            .verify(SSH_NAMESPACE, data, &signature)
+            .context("Invalid SSH signature")
+    }
+
+    fn verify_openpgp(&self, signature: &str, data: &[u8]) -> Result<()> {
+        let (signature, _) =
+            DetachedSignature::from_string(signature).context("Malformed OpenPGP signature")?;
+
+        for key in &self.openpgp {
+            if signature.verify(key, data).is_ok() {
+                return Ok(());
+            }
+
+            for subkey in &key.public_subkeys {
+                if signature.verify(subkey, data).is_ok() {
+                    return Ok(());
+                }
+            }
+        }
+
+        bail!("Signed by untrusted OpenPGP key")
+    }
+}
+
+/// Parses an armored or binary OpenPGP public key.
+fn openpgp_key(input: &[u8]) -> Result<SignedPublicKey> {
+    let key = if input.starts_with(b"-----BEGIN") {
+        SignedPublicKey::from_armor_single(input)?.0
+    } else {
+        SignedPublicKey::from_bytes(input)?
+    };
+
+    key.verify_bindings()?;
+
+    Ok(key)
+}
+
+/// Parses the allowed signers format of `ssh-keygen`.
+///
+/// Entries restricted to other namespaces and entries with unsupported options are skipped.
+fn allowed_signers(input: &str) -> Result<Vec<SshKey>> {
+    let mut keys = Vec::new();
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+
+        let principals = fields
+            .next()
+            .expect("non-empty line")
+            .split(',')
+            .map(str::to_owned)
+            .collect();
+
+        // Options are given between the principals and the key
+        let mut supported = true;
+        let mut field = fields.next();
+        while let Some(options) = field.filter(|field| !is_key_type(field)) {
+            for option in split_options(options) {
+                match option.split_once('=') {
+                    Some(("namespaces", namespaces)) => {
+                        supported &= namespaces
+                            .trim_matches('"')
+                            .split(',')
+                            .any(|namespace| matches(namespace, SSH_NAMESPACE));
+                    }
+                    _ => supported = false,
+                }
+            }
+
+            field = fields.next();
+        }
+
+        let (Some(key_type), Some(key_data)) = (field, fields.next()) else {
+            bail!("Missing key in line {}", index + 1);
+        };
+
+        let key = PublicKey::from_openssh(&format!("{} {}", key_type, key_data))
+            .with_context(|| format!("Invalid key in line {}", index + 1))?;
+
+        if supported {
+            keys.push(SshKey {
+                key,
+                principals: Some(principals),
+            });
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Splits comma separated options, keeping commas inside of quoted values.
+fn split_options(options: &str) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    options.split(move |c| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        c == ',' && !quoted
+    })
+}
+
+fn is_key_type(field: &str) -> bool {
+    field.starts_with("ssh-") || field.starts_with("ecdsa-") || field.starts_with("sk-")
+}
+
+/// Matches a principal pattern supporting the `*` and `?` wildcards.
+fn matches(pattern: &str, value: &str) -> bool {
+    fn matches(pattern: &[u8], value: &[u8]) -> bool {
+        match (pattern.split_first(), value.split_first()) {
+            (None, None) => true,
+            (Some((b'*', rest)), _) => {
+                matches(rest, value) || (!value.is_empty() && matches(pattern, &value[1..]))
+            }
+            (Some((b'?', rest)), Some((_, value))) => matches(rest, value),
+            (Some((p, rest)), Some((v, value))) if p == v => matches(rest, value),
+            _ => false,
+        }
+    }
+
+    matches(pattern.as_bytes(), value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pgp::composed::{ArmorOptions, KeyType, SecretKeyParamsBuilder, SignedSecretKey};
+    use pgp::crypto::hash::HashAlgorithm;
+    use pgp::types::Password;
+    use rand_chacha::rand_core::SeedableRng;
+    use ssh_key::private::Ed25519Keypair;
+    use ssh_key::{LineEnding, PrivateKey};
+    use std::path::Path;
+
+    fn ssh_key(seed: u8) -> PrivateKey {
+        PrivateKey::from(Ed25519Keypair::from_seed(&[seed; 32]))
+    }
+
+    fn openpgp_key(seed: u64) -> SignedSecretKey {
+        SecretKeyParamsBuilder::default()
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("Test <test@example.com>".into())
+            .build()
+            .unwrap()
+            .generate(rand_chacha::ChaCha8Rng::seed_from_u64(seed))
+            .unwrap()
+    }
+
+    type Sign<'a> = &'a dyn Fn(&[u8]) -> String;
+
+    /// Creates an empty commit in a new repository, signed by the given function if any.
+    fn commit(dir: &Path, email: &str, sign: Option<Sign>) -> (git2::Repository, git2::Oid) {
+        let repository = git2::Repository::init(dir).unwrap();
+        let tree = repository.index().unwrap().write_tree().unwrap();
+        let tree = repository.find_tree(tree).unwrap();
+        let signature = git2::Signature::now("Test", email).unwrap();
+
+        let commit = match sign {
+            Some(sign) => {
+                let buffer = repository
+                    .commit_create_buffer(&signature, &signature, "Test", &tree, &[])
+                    .unwrap();
+                let content = std::str::from_utf8(&buffer).unwrap();
+                repository
+                    .commit_signed(content, &sign(&buffer), None)
+                    .unwrap()
+            }
+            None => repository
+                .commit(None, &signature, &signature, "Test", &tree, &[])
+                .unwrap(),
+        };
+
+        drop(tree);
+        (repository, commit)
+    }
+
+    fn sign_ssh(key: &PrivateKey) -> impl Fn(&[u8]) -> String + '_ {
+        |data| {
+            key.sign(SSH_NAMESPACE, HashAlg::Sha512, data)
+                .unwrap()
+                .to_pem(LineEnding::LF)
+                .unwrap()
+        }
+    }
+
+    fn sign_openpgp(key: &SignedSecretKey) -> impl Fn(&[u8]) -> String + '_ {
+        |data| {
+            DetachedSignature::sign_binary_data(
+                rand_chacha::ChaCha8Rng::seed_from_u64(0),
+                &key.primary_key,
+                &Password::empty(),
+                HashAlgorithm::Sha256,
+                data,
+            )
+            .unwrap()
+            .to_armored_string(ArmorOptions::default())
+            .unwrap()
+        }
+    }
+
+    fn trusted_ssh(key: &PrivateKey, principals: Option<&[&str]>) -> Keys {
+        Keys {
+            ssh: vec![SshKey {
+                key: key.public_key().clone(),
+                principals: principals
+                    .map(|principals| principals.iter().map(|p| p.to_string()).collect()),
+            }],
+            openpgp: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("alice@example.com", "alice@example.com"));
+        assert!(matches("*@example.com", "alice@example.com"));
+        assert!(matches("*", ""));
+        assert!(matches("?lice@*", "alice@example.com"));
+        assert!(!matches("?lice@*", "lice@example.com"));
+        assert!(!matches("*@example.com", "alice@example.org"));
+        assert!(!matches("alice", "alice@example.com"));
+    }
+
+    #[test]
+    fn allowed_signers_principals() {
+        let key = ssh_key(1).public_key().to_openssh().unwrap();
+        let input = format!("# Comment\n\nalice@example.com,*@example.org {}\n", key);
+
+        let keys = allowed_signers(&input).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            keys[0].principals.as_deref(),
+            Some(&["alice@example.com".to_owned(), "*@example.org".to_owned()][..])
+        );
+
+        assert!(keys[0].allows(Some("alice@example.com")));
+        assert!(keys[0].allows(Some("bob@example.org")));
+        assert!(!keys[0].allows(Some("bob@example.com")));
+        assert!(!keys[0].allows(None));
+    }
+
+    #[test]
+    fn allowed_signers_options() {
+        let key = ssh_key(1).public_key().to_openssh().unwrap();
+        let input = [
+            format!("git@example.com namespaces=\"git\" {}", key),
+            format!("file@example.com namespaces=\"file\" {}", key),
+            format!("both@example.com namespaces=\"file,g*\" {}", key),
+            format!("ca@example.com cert-authority {}", key),
+            format!(
+                "expiry@example.com valid-before=20300101,namespaces=\"git\" {}",
+                key
+            ),
+        ]
+        .join("\n");
+
+        let keys = allowed_signers(&input).unwrap();
+        let principals: Vec<_> = keys
+            .iter()
+            .flat_map(|key| key.principals.clone().unwrap())
+            .collect();
+        assert_eq!(principals, ["git@example.com", "both@example.com"]);
+    }
+
+    #[test]
+    fn allowed_signers_invalid() {
+        assert!(allowed_signers("alice@example.com").is_err());
+        assert!(allowed_signers("alice@example.com ssh-ed25519 invalid").is_err());
+    }
+
+    #[test]
+    fn verify_ssh() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ssh_key(1);
+        let (repository, commit) = commit(dir.path(), "alice@example.com", Some(&sign_ssh(&key)));
+
+        assert!(trusted_ssh(&key, None).verify(&repository, commit).is_ok());
+        assert!(trusted_ssh(&key, Some(&["*@example.com"]))
+            .verify(&repository, commit)
+            .is_ok());
+
+        // Trusted key, but not for the committer
+        assert!(trusted_ssh(&key, Some(&["bob@example.com"]))
+            .verify(&repository, commit)
+            .is_err());
+
+        // Untrusted key
+        assert!(trusted_ssh(&ssh_key(2), None)
+            .verify(&repository, commit)
+            .is_err());
+    }
+
+    #[test]
+    fn verify_ssh_tampered() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ssh_key(1);
+        let sign = |data: &[u8]| sign_ssh(&key)(&[data, b"tampered"].concat());
+        let (repository, commit) = commit(dir.path(), "alice@example.com", Some(&sign));
+
+        assert!(trusted_ssh(&key, None).verify(&repository, commit).is_err());
+    }
+
+    #[test]
+    fn verify_openpgp() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = openpgp_key(1);
+        let (repository, commit) =
+            commit(dir.path(), "test@example.com", Some(&sign_openpgp(&key)));
+
+        let trusted = Keys {
+            ssh: Vec::new(),
+            openpgp: vec![key.to_public_key()],
+        };
+        assert!(trusted.verify(&repository, commit).is_ok());
+
+        let untrusted = Keys {
+            ssh: Vec::new(),
+            openpgp: vec![openpgp_key(2).to_public_key()],
+        };
+        assert!(untrusted.verify(&repository, commit).is_err());
+    }
+
+    #[test]
+    fn verify_unsigned() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ssh_key(1);
+        let (repository, commit) = commit(dir.path(), "alice@example.com", None);
+
+        assert!(trusted_ssh(&key, None).verify(&repository, commit).is_err());
+    }
+}
@@ -1,48 +1,109 @@
+use crate::api;
 use crate::config::Webhook;
-use crate::repo::Repo;
+use crate::metrics::{self, Outcome, WebhookLabels, METRICS};
+use crate::queue::{Queue, Trigger};
+use crate::registry::Registry;
 use anyhow::Result;
-use axum::routing::get;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
 use axum::Router;
 use std::future::Future;
 use std::sync::Arc;
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, Instrument};
 
 mod github;
 mod gitlab;
 mod plain;
 
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Provider {
+    Plain,
+    GitHub,
+    GitLab,
+}
+
+impl Provider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Provider::Plain => "plain",
+            Provider::GitHub => "github",
+            Provider::GitLab => "gitlab",
+        }
+    }
+}
+
 async fn root() -> &'static str {
     "pullomatic webhook server"
 }
 
+async fn handle(
+    State((registry, queue)): State<(Arc<Registry>, Queue)>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(), (StatusCode, &'static str)> {
+    // Look up the repo on every request as the config can change at any time
+    let repo = registry
+        .get(&name)
+        .ok_or((StatusCode::NOT_FOUND, "Repository not found"))?;
+
+    let config = repo.config();
+    let Some(ref webhook) = config.webhook else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Repository not configured for webhooks",
+        ));
+    };
+
+    let task = async {
+        let (provider, result) = match webhook {
+            Webhook::Plain(config) => (Provider::Plain, plain::handle(config).await),
+            Webhook::GitHub(config) => (
+                Provider::GitHub,
+                github::handle(config, &repo, &headers, &body).await,
+            ),
+            Webhook::GitLab(config) => (
+                Provider::GitLab,
+                gitlab::handle(config, &repo, &headers, &body).await,
+            ),
+        };
+
+        let outcome = match result {
+            Ok(true) => Outcome::Triggered,
+            Ok(false) => Outcome::Ignored,
+            Err(_) => Outcome::Rejected,
+        };
+        METRICS
+            .webhook_requests
+            .get_or_create(&WebhookLabels { provider, outcome })
+            .inc();
+
+        if result? {
+            debug!("Trigger update from hook");
+            queue.trigger(&repo, Trigger::Webhook(provider));
+        }
+
+        Ok(())
+    };
+
+    task.instrument(info_span!("Handle webhook request", repo = repo.name))
+        .await
+}
+
 pub fn serve(
     addr: String,
     running: CancellationToken,
-    producer: tokio::sync::mpsc::Sender<Arc<Repo>>,
-    repos: &[Arc<Repo>],
+    queue: Queue,
+    registry: Arc<Registry>,
 ) -> impl Future<Output = Result<()>> + use<> {
-    let mut app = Router::new().route("/", get(root));
-
-    for repo in repos {
-        let Some(ref config) = repo.config.webhook else {
-            continue;
-        };
-
-        app = app.nest(
-            &format!("/{}", repo.name),
-            match config {
-                Webhook::Plain(config) => {
-                    plain::router(config.clone(), producer.clone(), repo.clone())
-                }
-                Webhook::GitHub(config) => {
-                    github::router(config.clone(), producer.clone(), repo.clone())
-                }
-                Webhook::GitLab(config) => {
-                    gitlab::router(config.clone(), producer.clone(), repo.clone())
-                }
-            },
-        );
-    }
+    let app = Router::new()
+        .route("/", get(root))
+        .route("/{name}", post(handle))
+        .with_state((registry.clone(), queue.clone()))
+        .route("/metrics", get(metrics::handle))
+        .merge(api::router(registry, queue));
 
     async move {
         let listener = tokio::net::TcpListener::bind(addr).await?;